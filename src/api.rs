@@ -0,0 +1,229 @@
+use std::sync::Arc;
+
+use http::header::{HeaderValue, WWW_AUTHENTICATE};
+use http::StatusCode;
+use json_patch::PatchError;
+use serde_json::Value;
+use structopt::StructOpt;
+use warp::{Filter as _, Reply as _};
+
+use crate::cache::RenderCache;
+use crate::value;
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(
+        name = "api-token",
+        long,
+        value_name = "TOKEN",
+        env = "HANDLEBARS_SERVER_API_TOKEN",
+        hide_env_values = true,
+        help = "Enable the API for updating the value at `/_api/value` and reading render cache statistics at `/_api/cache`, authenticated with the given bearer token"
+    )]
+    token: Option<String>,
+    #[structopt(
+        name = "api-max-body-size",
+        long,
+        value_name = "BYTES",
+        default_value = "16777216",
+        help = "Maximum size of request bodies accepted by the API"
+    )]
+    max_body_size: u64,
+}
+
+/// Rejection for API requests without the expected bearer token.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+enum Operation {
+    Replace(String),
+    MergePatch,
+    Patch,
+}
+
+pub fn api(
+    options: &Options,
+    value_tx: value::Sender,
+    cache: RenderCache,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let enabled = options.token.is_some();
+    let expected_authorization: Arc<str> = match &options.token {
+        Some(token) => format!("Bearer {}", token).into(),
+        None => "".into(),
+    };
+    let enabled = warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
+    // Checked before reading the body, so unauthenticated clients can't make the server buffer
+    // large requests.
+    let authorized = warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let expected_authorization = expected_authorization.clone();
+            async move {
+                match authorization {
+                    Some(authorization)
+                        if ring::constant_time::verify_slices_are_equal(
+                            authorization.as_bytes(),
+                            expected_authorization.as_bytes(),
+                        )
+                        .is_ok() =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one();
+
+    let cache_stats = warp::path!("_api" / "cache")
+        .and(enabled)
+        .and(warp::get())
+        .and(authorized.clone())
+        .map(move || warp::reply::json(&cache.stats()));
+
+    let replace =
+        warp::put()
+            .and(warp::path::tail())
+            .and_then(|tail: warp::path::Tail| async move {
+                match urlencoding::decode(tail.as_str()) {
+                    Ok(path) if path.is_empty() => Ok(Operation::Replace(path)),
+                    Ok(path) => Ok(Operation::Replace(format!("/{}", path))),
+                    Err(_) => Err(warp::reject::not_found()),
+                }
+            });
+    let merge_patch = warp::patch()
+        .and(warp::path::end())
+        .map(|| Operation::MergePatch);
+    let patch = warp::post().and(warp::path::end()).map(|| Operation::Patch);
+
+    let value = warp::path!("_api" / "value" / ..)
+        .and(enabled)
+        .and(replace.or(merge_patch).unify().or(patch).unify())
+        .and(authorized)
+        .and(warp::body::content_length_limit(options.max_body_size))
+        .and(warp::body::bytes())
+        .map(move |operation: Operation, body: bytes::Bytes| {
+            match update(&value_tx, operation, &body) {
+                Ok(()) => {
+                    log::info!("got updated JSON value from API");
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                Err((status, message)) => {
+                    log::warn!("failed to update JSON value from API: {}", message);
+                    warp::reply::with_status(message, status).into_response()
+                }
+            }
+        });
+
+    cache_stats
+        .or(value)
+        .recover(|rejection: warp::Rejection| async move {
+            if rejection.find::<Unauthorized>().is_some() {
+                Ok(warp::reply::with_header(
+                    StatusCode::UNAUTHORIZED,
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Bearer"),
+                ))
+            } else {
+                Err(rejection)
+            }
+        })
+}
+
+fn update(
+    value_tx: &value::Sender,
+    operation: Operation,
+    body: &[u8],
+) -> Result<(), (StatusCode, String)> {
+    let bad_request = |err: serde_json::Error| (StatusCode::BAD_REQUEST, err.to_string());
+    let patch_error = |err: PatchError| match err {
+        PatchError::InvalidPointer => (StatusCode::NOT_FOUND, err.to_string()),
+        PatchError::TestFailed => (StatusCode::CONFLICT, err.to_string()),
+    };
+
+    match operation {
+        Operation::Replace(path) => {
+            let new_value = serde_json::from_slice(body).map_err(bad_request)?;
+            value_tx
+                .update(|value| replace(value, &path, new_value))
+                .map_err(patch_error)
+        }
+        Operation::MergePatch => {
+            let patch = serde_json::from_slice(body).map_err(bad_request)?;
+            value_tx.update(|value| {
+                json_patch::merge(value, &patch);
+                Ok(())
+            })
+        }
+        Operation::Patch => {
+            let patch = serde_json::from_slice(body).map_err(bad_request)?;
+            value_tx
+                .update(|value| json_patch::patch(value, &patch))
+                .map_err(patch_error)
+        }
+    }
+}
+
+/// Sets the value at the JSON pointer `path`, replacing it if it exists. Missing keys are only
+/// added to objects, so array elements are never inserted or appended.
+fn replace(value: &mut Value, path: &str, new_value: Value) -> Result<(), PatchError> {
+    if let Some(target) = value.pointer_mut(path) {
+        *target = new_value;
+        return Ok(());
+    }
+
+    let (parent, key) = path.rsplit_once('/').ok_or(PatchError::InvalidPointer)?;
+    match value.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(key.replace("~1", "/").replace("~0", "~"), new_value);
+            Ok(())
+        }
+        _ => Err(PatchError::InvalidPointer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn replace_array_element_in_place() {
+        let mut value = json!({ "items": [1, 2, 3] });
+        replace(&mut value, "/items/0", json!(9)).unwrap();
+        assert_eq!(value, json!({ "items": [9, 2, 3] }));
+    }
+
+    #[test]
+    fn replace_does_not_insert_into_arrays() {
+        let mut value = json!({ "items": [1, 2, 3] });
+        assert!(replace(&mut value, "/items/-", json!(9)).is_err());
+        assert!(replace(&mut value, "/items/3", json!(9)).is_err());
+        assert_eq!(value, json!({ "items": [1, 2, 3] }));
+    }
+
+    #[test]
+    fn replace_adds_missing_object_keys() {
+        let mut value = json!({ "o": {} });
+        replace(&mut value, "/o/a~1b", json!(1)).unwrap();
+        assert_eq!(value, json!({ "o": { "a/b": 1 } }));
+        assert!(replace(&mut value, "/missing/a", json!(1)).is_err());
+    }
+
+    #[test]
+    fn replace_whole_value() {
+        let mut value = json!([1]);
+        replace(&mut value, "", json!({ "a": 1 })).unwrap();
+        assert_eq!(value, json!({ "a": 1 }));
+    }
+}
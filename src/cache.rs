@@ -0,0 +1,131 @@
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use serde::Serialize;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        default_value = "67108864",
+        help = "Maximum total size of rendered pages to keep in memory, or 0 to disable caching"
+    )]
+    cache_size: usize,
+}
+
+/// Identifies the output of rendering a template. Output of outdated templates or values is
+/// never returned, since their generation or version differs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub name: String,
+    pub pointer: String,
+    /// The route parameters serialized as JSON.
+    pub params: String,
+    pub generation: u64,
+    pub version: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub size: usize,
+}
+
+struct Inner {
+    entries: LruCache<Key, Arc<str>>,
+    max_size: usize,
+    /// The newest templates generation and value version seen. Entries for older ones are
+    /// dropped when these change.
+    current: (u64, u64),
+    stats: Stats,
+}
+
+/// An LRU cache of rendered pages, limited by their total size.
+#[derive(Clone)]
+pub struct RenderCache(Arc<Mutex<Inner>>);
+
+impl RenderCache {
+    pub fn new(options: &Options) -> Self {
+        RenderCache(Arc::new(Mutex::new(Inner {
+            entries: LruCache::unbounded(),
+            max_size: options.cache_size,
+            current: (0, 0),
+            stats: Stats::default(),
+        })))
+    }
+
+    /// Returns the cached output for `key`, or renders and caches it with `render`. Along with the
+    /// output, `render` returns whether it may be cached, which is returned too.
+    pub fn get_or_render<E>(
+        &self,
+        key: Key,
+        render: impl FnOnce() -> Result<(String, bool), E>,
+    ) -> Result<(Arc<str>, bool), E> {
+        {
+            let mut inner = self.0.lock().unwrap();
+            if inner.max_size == 0 {
+                drop(inner);
+                return render().map(|(output, cacheable)| (output.into(), cacheable));
+            }
+
+            inner.invalidate(&key);
+            if let Some(output) = inner.entries.get(&key).cloned() {
+                inner.stats.hits += 1;
+                log::debug!("cache hit for `{}` at `{}`", key.name, key.pointer);
+                return Ok((output, true));
+            }
+            inner.stats.misses += 1;
+        }
+
+        // Render without holding the lock, so other pages can be served meanwhile.
+        let (output, cacheable) = render()?;
+        let output: Arc<str> = output.into();
+        if !cacheable {
+            return Ok((output, false));
+        }
+
+        let mut inner = self.0.lock().unwrap();
+        inner.invalidate(&key);
+        if (key.generation, key.version) == inner.current && output.len() <= inner.max_size {
+            inner.stats.size += output.len();
+            if let Some(old) = inner.entries.put(key, output.clone()) {
+                inner.stats.size -= old.len();
+            }
+            while inner.stats.size > inner.max_size {
+                match inner.entries.pop_lru() {
+                    Some((_, evicted)) => {
+                        inner.stats.size -= evicted.len();
+                        inner.stats.evictions += 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+        Ok((output, true))
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = self.0.lock().unwrap();
+        Stats {
+            entries: inner.entries.len(),
+            ..inner.stats.clone()
+        }
+    }
+}
+
+impl Inner {
+    /// Drops all entries if `key` is for newer templates or a newer value than seen before.
+    fn invalidate(&mut self, key: &Key) {
+        let (generation, version) = self.current;
+        if key.generation > generation || key.version > version {
+            self.current = (key.generation.max(generation), key.version.max(version));
+            self.entries.clear();
+            self.stats.size = 0;
+        }
+    }
+}
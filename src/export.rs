@@ -0,0 +1,261 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use fn_error_context::context;
+use serde_json::{Map, Value};
+use structopt::StructOpt;
+use walkdir::WalkDir;
+
+use crate::render::{self, ErrorContext};
+use crate::routes::Routes;
+use crate::templates::Templates;
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(
+        value_name = "OUT_DIR",
+        help = "Directory to write the generated files to",
+        parse(from_os_str)
+    )]
+    out: PathBuf,
+}
+
+#[context("failed to export site to `{}`", options.out.display())]
+pub fn run(
+    base: &Path,
+    excluded: &[PathBuf],
+    options: &Options,
+    render_options: &render::Options,
+    templates: &Templates,
+    routes: &Routes,
+    value: &Value,
+) -> Result<()> {
+    log::info!("exporting site to `{}`", options.out.display());
+    fs::create_dir_all(&options.out)?;
+
+    let static_count = copy_static_files(base, excluded, &options.out)?;
+    log::info!("copied {} static files", static_count);
+
+    let mut page_count = 0;
+    let mut error_count = 0;
+    for name in templates.pages().filter(|name| !name.contains('/')) {
+        let data = templates.front_matter(name).data.as_deref();
+        let value = match value.pointer(data.unwrap_or("")) {
+            Some(value) => value,
+            None => {
+                log::warn!(
+                    "skipping template `{}`: pointer error: {}",
+                    name,
+                    data.unwrap_or("")
+                );
+                continue;
+            }
+        };
+
+        let mut template_page_count = 0;
+        visit(value, &mut Vec::new(), &mut |segments, subvalue| {
+            let html = match templates.render(name, subvalue, &Value::Null) {
+                Ok(html) => html,
+                // Every template is tried against every value, so most don't have the fields a
+                // template uses or have them with other types. Only the value a template's front
+                // matter points to must render.
+                Err(err) if data.is_none() || !segments.is_empty() => {
+                    log::debug!(
+                        "skipping template `{}` at `{}`: {}",
+                        name,
+                        to_pointer(segments),
+                        err
+                    );
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::warn!(
+                        "failed to render template `{}` at `{}`: {}",
+                        name,
+                        to_pointer(segments),
+                        err
+                    );
+                    error_count += 1;
+                    return Ok(());
+                }
+            };
+
+            let html = render::minify(render_options, templates, name, html);
+            let mut path = options.out.clone();
+            path.extend(segments);
+            path.push(templates.file_name(name));
+            write_file(&path, html.as_bytes())?;
+            template_page_count += 1;
+            Ok(())
+        })?;
+
+        if template_page_count == 0 {
+            log::warn!(
+                "template `{}` was not rendered for any part of the value",
+                name
+            );
+        }
+        page_count += template_page_count;
+    }
+
+    // Routes are written as the `index.html` of a directory, which static hosts serve at the
+    // route's path, unless the path ends with a file name.
+    for (path, name, pointer) in routes.exported(templates) {
+        if !templates.is_page(&name) {
+            log::warn!("template `{}` for route `{}` does not exist", name, path);
+            error_count += 1;
+            continue;
+        }
+        let file = match route_file(&options.out, &path) {
+            Some(file) => file,
+            None => {
+                log::warn!(
+                    "skipping route `{}`: path cannot be used as a file name",
+                    path
+                );
+                continue;
+            }
+        };
+        let value = match value.pointer(&pointer) {
+            Some(value) => value,
+            None => {
+                log::warn!("skipping route `{}`: pointer error: {}", path, pointer);
+                continue;
+            }
+        };
+
+        match templates.render(&name, value, &Value::Object(Map::new())) {
+            Ok(html) => {
+                let html = render::minify(render_options, templates, &name, html);
+                write_file(&file, html.as_bytes())?;
+                page_count += 1;
+            }
+            Err(err) => {
+                log::warn!("failed to render route `{}`: {}", path, err);
+                error_count += 1;
+            }
+        }
+    }
+
+    log::info!("rendered {} pages", page_count);
+
+    // Static hosts commonly serve `404.html` for missing files.
+    if templates.has_template("404") {
+        let context = ErrorContext::not_found("", "Not Found".into());
+        let html = templates.render("404", &serde_json::to_value(&context)?, &Value::Null)?;
+        let html = render::minify(render_options, templates, "404", html);
+        write_file(&options.out.join("404.html"), html.as_bytes())?;
+    }
+
+    if error_count > 0 {
+        anyhow::bail!("failed to render {} pages", error_count);
+    }
+    Ok(())
+}
+
+/// Calls `f` with the pointer segments of every value that can be addressed by a URL path.
+fn visit<'a, F>(value: &'a Value, segments: &mut Vec<String>, f: &mut F) -> Result<()>
+where
+    F: FnMut(&[String], &'a Value) -> Result<()>,
+{
+    f(segments, value)?;
+
+    let children: Box<dyn Iterator<Item = (String, &Value)>> = match value {
+        Value::Object(map) => Box::new(map.iter().map(|(key, value)| (escape(key), value))),
+        Value::Array(vec) => Box::new(
+            vec.iter()
+                .enumerate()
+                .map(|(idx, value)| (idx.to_string(), value)),
+        ),
+        _ => return Ok(()),
+    };
+
+    for (segment, child) in children {
+        if segment.is_empty() || segment == "." || segment == ".." || segment.contains('\\') {
+            log::warn!(
+                "skipping value at `{}/{}`: key cannot be used as a file name",
+                to_pointer(segments),
+                segment
+            );
+            continue;
+        }
+
+        segments.push(segment);
+        visit(child, segments, f)?;
+        segments.pop();
+    }
+    Ok(())
+}
+
+/// Returns the file to export the route `path` to, or `None` if a segment isn't a valid file name.
+fn route_file(out: &Path, path: &str) -> Option<PathBuf> {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    if segments
+        .iter()
+        .any(|segment| *segment == "." || *segment == ".." || segment.contains('\\'))
+    {
+        return None;
+    }
+
+    let mut file = out.to_owned();
+    file.extend(segments.iter().filter(|segment| !segment.is_empty()));
+    match segments.last() {
+        Some(last) if last.contains('.') => {}
+        _ => file.push("index.html"),
+    }
+    Some(file)
+}
+
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn to_pointer(segments: &[String]) -> String {
+    segments
+        .iter()
+        .map(|segment| format!("/{}", segment))
+        .collect()
+}
+
+/// Copies the files in `base` to `out`, except templates, hidden files and the canonical paths in
+/// `excluded`, like the data files.
+fn copy_static_files(base: &Path, excluded: &[PathBuf], out: &Path) -> Result<usize> {
+    let out = out.canonicalize()?;
+
+    let mut count = 0;
+    for entry in WalkDir::new(base).into_iter().filter_entry(|entry| {
+        if entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.') {
+            return false;
+        }
+        match entry.path().canonicalize() {
+            Ok(path) => path != out && !excluded.contains(&path),
+            Err(_) => true,
+        }
+    }) {
+        let entry = entry?;
+        let extension = entry.path().extension();
+        if !entry.file_type().is_file()
+            || extension == Some("hbs".as_ref())
+            || extension == Some("rhai".as_ref())
+        {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(base)?;
+        let contents = fs::read(entry.path())
+            .with_context(|| format!("failed to read file `{}`", entry.path().display()))?;
+        write_file(&out.join(relative), &contents)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[context("failed to write file `{}`", path.display())]
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
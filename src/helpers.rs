@@ -0,0 +1,646 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt::Write;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::{Map, Number, Value};
+
+type HelperFn = fn(&Helper) -> Result<Value, RenderError>;
+
+thread_local! {
+    /// Set when a helper whose output changes between renders, such as `now`, is used.
+    static VOLATILE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `render`, also returning whether its output depends on when it was rendered because it
+/// used a helper such as `now`. Such output mustn't be cached.
+pub fn track_volatile<T>(render: impl FnOnce() -> T) -> (T, bool) {
+    VOLATILE.with(|volatile| volatile.set(false));
+    let result = render();
+    (result, VOLATILE.with(Cell::take))
+}
+
+/// A helper which computes a value from its parameters, so it can be used in subexpressions. In
+/// strict mode, missing parameters are errors like with the built-in helpers.
+struct ValueHelper<F>(F);
+
+impl<F> HelperDef for ValueHelper<F>
+where
+    F: for<'reg, 'rc> Fn(&Helper<'reg, 'rc>) -> Result<Value, RenderError> + Send + Sync,
+{
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        if r.strict_mode() {
+            let mut params = h.params().iter().chain(h.hash().values());
+            if let Some(param) = params.find(|param| param.is_value_missing()) {
+                return Err(RenderError::strict_error(param.relative_path()));
+            }
+        }
+        (self.0)(h).map(ScopedJson::Derived)
+    }
+}
+
+/// Registers the built-in helpers. The comparison helpers replace the ones from handlebars, so
+/// that `eq`, `ne`, `gt`, `gte`, `lt` and `lte` all order values the same way, as in `sort`.
+pub fn register(handlebars: &mut Handlebars<'static>) {
+    let helpers: &[(&str, HelperFn)] = &[
+        ("date", date),
+        ("parse_date", parse_date),
+        ("now", now),
+        ("number", number),
+        ("currency", currency),
+        ("pluralize", pluralize),
+        ("upper", upper),
+        ("lower", lower),
+        ("capitalize", capitalize),
+        ("truncate", truncate),
+        ("json", json),
+        ("sort", sort),
+        ("group_by", group_by),
+        ("filter", filter),
+        ("slice", slice),
+        ("eq", |h| compare(h, |ord| ord == Ordering::Equal)),
+        ("ne", |h| compare(h, |ord| ord != Ordering::Equal)),
+        ("gt", |h| compare(h, |ord| ord == Ordering::Greater)),
+        ("gte", |h| compare(h, |ord| ord != Ordering::Less)),
+        ("lt", |h| compare(h, |ord| ord == Ordering::Less)),
+        ("lte", |h| compare(h, |ord| ord != Ordering::Greater)),
+        ("add", |h| arithmetic(h, i64::checked_add, |x, y| x + y)),
+        ("sub", |h| arithmetic(h, i64::checked_sub, |x, y| x - y)),
+        ("mul", |h| arithmetic(h, i64::checked_mul, |x, y| x * y)),
+        ("div", |h| arithmetic(h, |_, _| None, |x, y| x / y)),
+        ("mod", |h| arithmetic(h, i64::checked_rem, |x, y| x % y)),
+    ];
+
+    for &(name, helper) in helpers {
+        handlebars.register_helper(name, Box::new(ValueHelper(helper)));
+    }
+}
+
+/// Formats a date, given as an RFC 3339 string, a `YYYY-MM-DD` date or a unix timestamp.
+///
+/// `{{date published "%e %B %Y" tz="Europe/London"}}`
+fn date(h: &Helper) -> Result<Value, RenderError> {
+    let tz = timezone(h)?;
+    let date = match param(h, 0)? {
+        Value::String(string) => {
+            if let Ok(date) = DateTime::parse_from_rfc3339(string) {
+                date.with_timezone(&tz)
+            } else if let Ok(date) = NaiveDate::parse_from_str(string, "%Y-%m-%d") {
+                local_to_tz(h, &tz, date.and_hms_opt(0, 0, 0).unwrap())?
+            } else {
+                return Err(error(h, format!("invalid date `{}`", string)));
+            }
+        }
+        Value::Number(number) => match number
+            .as_i64()
+            .and_then(|timestamp| tz.timestamp_opt(timestamp, 0).single())
+        {
+            Some(date) => date,
+            None => return Err(error(h, format!("invalid timestamp `{}`", number))),
+        },
+        value => return Err(error(h, format!("invalid date `{}`", value))),
+    };
+    let format = str_param(h, 1)?;
+
+    let mut result = String::new();
+    write!(result, "{}", date.format(format))
+        .map_err(|_| error(h, format!("invalid format `{}`", format)))?;
+    Ok(Value::String(result))
+}
+
+/// Parses a date with the given format, returning it as an RFC 3339 string.
+///
+/// `{{date (parse_date "25/12/2020" "%d/%m/%Y") "%A"}}`
+fn parse_date(h: &Helper) -> Result<Value, RenderError> {
+    let tz = timezone(h)?;
+    let string = str_param(h, 0)?;
+    let format = str_param(h, 1)?;
+
+    let date = match NaiveDateTime::parse_from_str(string, format) {
+        Ok(date) => date,
+        Err(_) => match NaiveDate::parse_from_str(string, format) {
+            Ok(date) => date.and_hms_opt(0, 0, 0).unwrap(),
+            Err(err) => return Err(error(h, format!("invalid date `{}`: {}", string, err))),
+        },
+    };
+
+    Ok(Value::String(local_to_tz(h, &tz, date)?.to_rfc3339()))
+}
+
+/// The current time as an RFC 3339 string. Pages using it aren't cached.
+fn now(_: &Helper) -> Result<Value, RenderError> {
+    VOLATILE.with(|volatile| volatile.set(true));
+    Ok(Value::String(Utc::now().to_rfc3339()))
+}
+
+fn timezone(h: &Helper) -> Result<Tz, RenderError> {
+    match hash_str(h, "tz")? {
+        Some(name) => name
+            .parse()
+            .map_err(|_| error(h, format!("unknown time zone `{}`", name))),
+        None => Ok(Tz::UTC),
+    }
+}
+
+fn local_to_tz(h: &Helper, tz: &Tz, date: NaiveDateTime) -> Result<DateTime<Tz>, RenderError> {
+    tz.from_local_datetime(&date)
+        .earliest()
+        .ok_or_else(|| error(h, format!("`{}` does not exist in time zone {}", date, tz)))
+}
+
+/// Formats a number with a fixed number of decimal places and a thousands separator.
+///
+/// `{{number 1234.5 decimals=1}}` renders as `1,234.5`.
+fn number(h: &Helper) -> Result<Value, RenderError> {
+    let value = f64_param(h, 0)?;
+    let decimals = hash_u64(h, "decimals")?.unwrap_or(0) as usize;
+    let separator = hash_str(h, "separator")?.unwrap_or(",");
+
+    Ok(Value::String(format_number(value, decimals, separator)))
+}
+
+/// Formats an amount of money given an ISO 4217 currency code.
+///
+/// `{{currency 1234.5 "GBP"}}` renders as `£1,234.50`.
+fn currency(h: &Helper) -> Result<Value, RenderError> {
+    let value = f64_param(h, 0)?;
+    let code = str_param(h, 1)?;
+
+    let (symbol, default_decimals) = match code {
+        "USD" => (Some("$"), 2),
+        "EUR" => (Some("€"), 2),
+        "GBP" => (Some("£"), 2),
+        "JPY" => (Some("¥"), 0),
+        "INR" => (Some("₹"), 2),
+        _ => (None, 2),
+    };
+    let decimals = hash_u64(h, "decimals")?.unwrap_or(default_decimals) as usize;
+
+    let sign = if value < 0.0 { "-" } else { "" };
+    let amount = format_number(value.abs(), decimals, ",");
+    Ok(Value::String(match symbol {
+        Some(symbol) => format!("{}{}{}", sign, symbol, amount),
+        None => format!("{}{} {}", sign, amount, code),
+    }))
+}
+
+fn format_number(value: f64, decimals: usize, separator: &str) -> String {
+    let formatted = format!("{:.*}", decimals, value.abs());
+    let (int, frac) = match formatted.split_once('.') {
+        Some((int, frac)) => (int, Some(frac)),
+        None => (formatted.as_str(), None),
+    };
+
+    let mut result = String::new();
+    if value < 0.0 && formatted.bytes().any(|b| b.is_ascii_digit() && b != b'0') {
+        result.push('-');
+    }
+    for (idx, ch) in int.chars().enumerate() {
+        if idx != 0 && (int.len() - idx) % 3 == 0 {
+            result.push_str(separator);
+        }
+        result.push(ch);
+    }
+    if let Some(frac) = frac {
+        result.push('.');
+        result.push_str(frac);
+    }
+    result
+}
+
+/// Chooses the singular or plural form of a word based on a count.
+///
+/// `{{count}} {{pluralize count "comment"}}` or `{{pluralize count "person" "people"}}`
+fn pluralize(h: &Helper) -> Result<Value, RenderError> {
+    let count = f64_param(h, 0)?;
+    let singular = str_param(h, 1)?;
+
+    Ok(Value::String(if count == 1.0 {
+        singular.to_owned()
+    } else if h.param(2).is_some() {
+        str_param(h, 2)?.to_owned()
+    } else {
+        format!("{}s", singular)
+    }))
+}
+
+fn upper(h: &Helper) -> Result<Value, RenderError> {
+    Ok(Value::String(str_param(h, 0)?.to_uppercase()))
+}
+
+fn lower(h: &Helper) -> Result<Value, RenderError> {
+    Ok(Value::String(str_param(h, 0)?.to_lowercase()))
+}
+
+fn capitalize(h: &Helper) -> Result<Value, RenderError> {
+    let mut chars = str_param(h, 0)?.chars();
+    Ok(Value::String(match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }))
+}
+
+/// Shortens a string to at most `length` characters, including the suffix.
+///
+/// `{{truncate description 100 suffix="..."}}`
+fn truncate(h: &Helper) -> Result<Value, RenderError> {
+    let string = str_param(h, 0)?;
+    let length = u64_param(h, 1)? as usize;
+    let suffix = hash_str(h, "suffix")?.unwrap_or("…");
+
+    if string.chars().count() <= length {
+        return Ok(Value::String(string.to_owned()));
+    }
+    if suffix.chars().count() >= length {
+        return Ok(Value::String(suffix.chars().take(length).collect()));
+    }
+
+    let mut result: String = string
+        .chars()
+        .take(length.saturating_sub(suffix.chars().count()))
+        .collect();
+    result.truncate(result.trim_end().len());
+    result.push_str(suffix);
+    Ok(Value::String(result))
+}
+
+/// Serializes a value as JSON. Use a triple-stash to avoid HTML escaping.
+///
+/// `<script>const data = {{{json this pretty=true}}};</script>`
+fn json(h: &Helper) -> Result<Value, RenderError> {
+    let value = param(h, 0)?;
+    let result = if hash_bool(h, "pretty")?.unwrap_or(false) {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+    result
+        .map(Value::String)
+        .map_err(|err| error(h, err.to_string()))
+}
+
+/// Sorts an array, optionally by the value of a key in each item.
+///
+/// `{{#each (sort posts "date" desc=true)}}`
+fn sort(h: &Helper) -> Result<Value, RenderError> {
+    let mut array = array_param(h, 0)?.clone();
+    let key = if h.param(1).is_some() {
+        Some(str_param(h, 1)?)
+    } else {
+        None
+    };
+
+    array.sort_by(|a, b| match key {
+        Some(key) => cmp_values(lookup(a, key), lookup(b, key)),
+        None => cmp_values(a, b),
+    });
+    if hash_bool(h, "desc")?.unwrap_or(false) {
+        array.reverse();
+    }
+    Ok(Value::Array(array))
+}
+
+/// Groups the items of an array by the value of a key, preserving the order in which each key
+/// first occurs.
+///
+/// `{{#each (group_by posts "category")}}<h2>{{key}}</h2>{{#each items}}...{{/each}}{{/each}}`
+fn group_by(h: &Helper) -> Result<Value, RenderError> {
+    let array = array_param(h, 0)?;
+    let key = str_param(h, 1)?;
+
+    let mut groups: Vec<(&Value, Vec<Value>)> = Vec::new();
+    for item in array {
+        let value = lookup(item, key);
+        match groups.iter_mut().find(|(group, _)| *group == value) {
+            Some((_, items)) => items.push(item.clone()),
+            None => groups.push((value, vec![item.clone()])),
+        }
+    }
+
+    Ok(Value::Array(
+        groups
+            .into_iter()
+            .map(|(key, items)| {
+                let mut group = Map::new();
+                group.insert("key".to_owned(), key.clone());
+                group.insert("items".to_owned(), Value::Array(items));
+                Value::Object(group)
+            })
+            .collect(),
+    ))
+}
+
+/// Selects the items of an array where a key has the given value, or is truthy if no value is
+/// given.
+///
+/// `{{#each (filter posts "draft" false)}}`
+fn filter(h: &Helper) -> Result<Value, RenderError> {
+    let array = array_param(h, 0)?;
+    let key = str_param(h, 1)?;
+    let expected = h.param(2).map(|param| param.value());
+
+    Ok(Value::Array(
+        array
+            .iter()
+            .filter(|item| {
+                let value = lookup(item, key);
+                match expected {
+                    Some(expected) => value == expected,
+                    None => is_truthy(value),
+                }
+            })
+            .cloned()
+            .collect(),
+    ))
+}
+
+/// Selects a range of items from an array. Negative indices count from the end of the array.
+///
+/// `{{#each (slice posts 0 5)}}`
+fn slice(h: &Helper) -> Result<Value, RenderError> {
+    let array = array_param(h, 0)?;
+    let index = |idx: i64| {
+        if idx < 0 {
+            array.len().saturating_sub(idx.unsigned_abs() as usize)
+        } else {
+            (idx as usize).min(array.len())
+        }
+    };
+
+    let start = index(i64_param(h, 1)?);
+    let end = if h.param(2).is_some() {
+        index(i64_param(h, 2)?)
+    } else {
+        array.len()
+    };
+
+    Ok(Value::Array(
+        array.get(start..end.max(start)).unwrap_or(&[]).to_vec(),
+    ))
+}
+
+fn compare(h: &Helper, f: impl FnOnce(Ordering) -> bool) -> Result<Value, RenderError> {
+    Ok(Value::Bool(f(cmp_values(param(h, 0)?, param(h, 1)?))))
+}
+
+fn arithmetic(
+    h: &Helper,
+    int_op: impl FnOnce(i64, i64) -> Option<i64>,
+    float_op: impl FnOnce(f64, f64) -> f64,
+) -> Result<Value, RenderError> {
+    let x = number_param(h, 0)?;
+    let y = number_param(h, 1)?;
+
+    if let (Some(x), Some(y)) = (x.as_i64(), y.as_i64()) {
+        if let Some(result) = int_op(x, y) {
+            return Ok(Value::from(result));
+        }
+    }
+
+    let result = float_op(x.as_f64().unwrap_or(0.0), y.as_f64().unwrap_or(0.0));
+    Number::from_f64(result)
+        .map(Value::Number)
+        .ok_or_else(|| error(h, format!("result `{}` is not a valid number", result)))
+}
+
+/// Orders values of different types as null < bool < number < string < array < object. Arrays
+/// and objects are compared item by item, with objects ordered by key.
+fn cmp_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| cmp_values(a, b))
+            .find(|&ord| ord != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(a), Value::Object(b)) => a
+            .iter()
+            .zip(b)
+            .map(|((a_key, a), (b_key, b))| a_key.cmp(b_key).then_with(|| cmp_values(a, b)))
+            .find(|&ord| ord != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Gets a nested value by a dot-separated path such as `author.name`.
+fn lookup<'a>(value: &'a Value, key: &str) -> &'a Value {
+    key.split('.')
+        .try_fold(value, |value, key| match value {
+            Value::Object(map) => map.get(key),
+            Value::Array(array) => key.parse().ok().and_then(|idx: usize| array.get(idx)),
+            _ => None,
+        })
+        .unwrap_or(&Value::Null)
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(_) => true,
+    }
+}
+
+fn param<'a>(h: &'a Helper, idx: usize) -> Result<&'a Value, RenderError> {
+    h.param(idx)
+        .map(|param| param.value())
+        .ok_or_else(|| error(h, format!("missing parameter {}", idx)))
+}
+
+fn typed_param<'a, T>(
+    h: &'a Helper,
+    idx: usize,
+    expected: &str,
+    f: impl FnOnce(&'a Value) -> Option<T>,
+) -> Result<T, RenderError> {
+    let value = param(h, idx)?;
+    f(value).ok_or_else(|| {
+        error(
+            h,
+            format!(
+                "expected parameter {} to be {}, got `{}`",
+                idx, expected, value
+            ),
+        )
+    })
+}
+
+fn str_param<'a>(h: &'a Helper, idx: usize) -> Result<&'a str, RenderError> {
+    typed_param(h, idx, "a string", Value::as_str)
+}
+
+fn f64_param(h: &Helper, idx: usize) -> Result<f64, RenderError> {
+    typed_param(h, idx, "a number", Value::as_f64)
+}
+
+fn i64_param(h: &Helper, idx: usize) -> Result<i64, RenderError> {
+    typed_param(h, idx, "an integer", Value::as_i64)
+}
+
+fn u64_param(h: &Helper, idx: usize) -> Result<u64, RenderError> {
+    typed_param(h, idx, "a non-negative integer", Value::as_u64)
+}
+
+fn number_param<'a>(h: &'a Helper, idx: usize) -> Result<&'a Number, RenderError> {
+    typed_param(h, idx, "a number", |value| match value {
+        Value::Number(number) => Some(number),
+        _ => None,
+    })
+}
+
+fn array_param<'a>(h: &'a Helper, idx: usize) -> Result<&'a Vec<Value>, RenderError> {
+    typed_param(h, idx, "an array", Value::as_array)
+}
+
+fn typed_hash<'a, T>(
+    h: &'a Helper,
+    name: &str,
+    expected: &str,
+    f: impl FnOnce(&'a Value) -> Option<T>,
+) -> Result<Option<T>, RenderError> {
+    match h.hash_get(name) {
+        Some(value) => match f(value.value()) {
+            Some(value) => Ok(Some(value)),
+            None => Err(error(
+                h,
+                format!(
+                    "expected `{}` to be {}, got `{}`",
+                    name,
+                    expected,
+                    value.value()
+                ),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+fn hash_str<'a>(h: &'a Helper, name: &str) -> Result<Option<&'a str>, RenderError> {
+    typed_hash(h, name, "a string", Value::as_str)
+}
+
+fn hash_u64(h: &Helper, name: &str) -> Result<Option<u64>, RenderError> {
+    typed_hash(h, name, "a non-negative integer", Value::as_u64)
+}
+
+fn hash_bool(h: &Helper, name: &str) -> Result<Option<bool>, RenderError> {
+    typed_hash(h, name, "a boolean", Value::as_bool)
+}
+
+fn error(h: &Helper, message: impl AsRef<str>) -> RenderError {
+    RenderError::new(format!("`{}` helper: {}", h.name(), message.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str, data: &Value) -> Result<String, handlebars::RenderError> {
+        let mut handlebars = Handlebars::new();
+        register(&mut handlebars);
+        handlebars.render_template(template, data)
+    }
+
+    #[test]
+    fn format_number_signs() {
+        assert_eq!(format_number(1234567.0, 0, ","), "1,234,567");
+        assert_eq!(format_number(-1234.5, 1, ","), "-1,234.5");
+        assert_eq!(format_number(-999.5, 0, " "), "-1 000");
+        assert_eq!(format_number(-0.004, 2, ","), "0.00");
+        assert_eq!(format_number(-0.0, 0, ","), "0");
+    }
+
+    #[test]
+    fn truncate_suffix() {
+        let data = json!({"s": "hello world"});
+        assert_eq!(render("{{truncate s 20}}", &data).unwrap(), "hello world");
+        assert_eq!(render("{{truncate s 6}}", &data).unwrap(), "hello…");
+        assert_eq!(
+            render(r#"{{truncate s 9 suffix="..."}}"#, &data).unwrap(),
+            "hello..."
+        );
+        assert_eq!(
+            render(r#"{{truncate s 2 suffix="..."}}"#, &data).unwrap(),
+            ".."
+        );
+        assert_eq!(render(r#"{{truncate s 0}}"#, &data).unwrap(), "");
+    }
+
+    #[test]
+    fn date_invalid_format() {
+        let data = json!({"d": "2020-12-25"});
+        assert_eq!(
+            render(r#"{{date d "%d/%m/%Y"}}"#, &data).unwrap(),
+            "25/12/2020"
+        );
+        assert!(render(r#"{{date d "%Q"}}"#, &data).is_err());
+    }
+
+    #[test]
+    fn comparisons() {
+        let data = json!({"a": {"x": 1}, "b": {"x": 2}, "n": 1, "f": 1.0});
+        assert_eq!(
+            render("{{eq a b}} {{ne a b}} {{lt a b}}", &data).unwrap(),
+            "false true true"
+        );
+        assert_eq!(
+            render("{{eq n f}} {{gte n f}} {{gt n f}}", &data).unwrap(),
+            "true true false"
+        );
+    }
+
+    #[test]
+    fn strict_missing_params() {
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        register(&mut handlebars);
+        let data = json!({"s": "a"});
+        assert_eq!(
+            handlebars.render_template("{{upper s}}", &data).unwrap(),
+            "A"
+        );
+        let err = handlebars
+            .render_template("{{truncate s 1 suffix=x}}", &data)
+            .unwrap_err();
+        assert_eq!(err.desc, "Variable \"x\" not found in strict mode.");
+        assert!(handlebars.render_template("{{upper t}}", &data).is_err());
+    }
+
+    #[test]
+    fn now_is_volatile() {
+        let (_, volatile) = track_volatile(|| render("{{upper \"a\"}}", &Value::Null));
+        assert!(!volatile);
+        let (_, volatile) = track_volatile(|| render("{{date (now) \"%Y\"}}", &Value::Null));
+        assert!(volatile);
+    }
+}
@@ -1,6 +1,11 @@
+mod api;
+mod cache;
+mod export;
+mod helpers;
 mod notify;
 mod reload;
 mod render;
+mod routes;
 mod server;
 mod templates;
 mod tls;
@@ -8,6 +13,7 @@ mod value;
 
 use std::ffi::{OsStr, OsString};
 use std::path::PathBuf;
+use std::process;
 use std::time::Duration;
 
 use anyhow::Result;
@@ -16,19 +22,33 @@ use tokio::runtime::Runtime;
 use tokio::sync::broadcast;
 use warp::Filter as _;
 
-use self::reload::reload;
+use self::api::api;
+use self::reload::{inject_script, reload, PageDependencies};
 use self::render::render;
+use self::routes::routes;
 
 const VERSION: &str = concat!(clap::crate_version!(), " (", env!("VERGEN_SHA_SHORT"), ")");
 const LONG_VERSION: &str = concat!(clap::crate_version!(), " (", env!("VERGEN_SHA"), ")");
 
-/// A simple server that generates HTML at runtime, based on JSON values piped to stdin.
+/// A simple server that generates HTML at runtime, based on JSON values piped to stdin or read from data files.
 #[derive(Debug, StructOpt)]
 #[structopt(version = VERSION, long_version = LONG_VERSION)]
 #[structopt(setting = clap::AppSettings::UnifiedHelpMessage)]
 pub struct Options {
     #[structopt(flatten)]
     server: server::Options,
+    #[structopt(flatten)]
+    value: value::Options,
+    #[structopt(flatten)]
+    api: api::Options,
+    #[structopt(flatten)]
+    routes: routes::Options,
+    #[structopt(flatten)]
+    render: render::Options,
+    #[structopt(flatten)]
+    reload: reload::Options,
+    #[structopt(flatten)]
+    cache: cache::Options,
     #[structopt(value_name = "BASE_DIR", help = "Base directory", default_value = ".", parse(try_from_os_str = parse_dir))]
     base: PathBuf,
     #[structopt(
@@ -37,36 +57,98 @@ pub struct Options {
         help = "Whether to watch for changes in the base directory"
     )]
     watch: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Renders every page to a directory, alongside a copy of the static files in the base directory.
+    /// Routes are exported too, except those with parameters.
+    #[structopt(name = "export")]
+    Export(export::Options),
 }
 
 fn main() {
     let mut runtime = Runtime::new().unwrap();
 
     env_logger::init_from_env(env_logger::Env::new().filter_or("HANDLEBARS_SERVER_LOG", "info"));
-    if let Err(err) = runtime.block_on(run()) {
+    let result = runtime.block_on(run());
+    runtime.shutdown_timeout(Duration::from_secs(0));
+
+    if let Err(err) = result {
         log::error!("Fatal error: {:#}", err);
+        process::exit(1);
     }
-
-    runtime.shutdown_timeout(Duration::from_secs(0))
 }
 
 async fn run() -> Result<()> {
-    let options = Options::from_args();
+    let mut options = Options::from_args();
     log::debug!("{:#?}", options);
 
-    let (reload_tx, _) = broadcast::channel(1);
+    // A change can send several events at once, like a resolved error followed by a reload.
+    let (reload_tx, _) = broadcast::channel(16);
+
+    if let Some(Command::Export(export_options)) = &options.command {
+        let templates = templates::load_templates(&options.base)?;
+        let routes_table = routes::load(&options, reload_tx.clone())?;
+        let (_, value_rx) = value::channel(&options, reload_tx)?;
+        let mut excluded = options.value.data_paths();
+        excluded.extend(options.routes.file());
+        return export::run(
+            &options.base,
+            &excluded,
+            export_options,
+            &options.render,
+            &templates,
+            &*routes_table.read().await,
+            &value_rx.borrow().value,
+        );
+    }
+
+    options.render.disable_minify_when_watching(options.watch);
+    options
+        .server
+        .disable_precompressed_when_watching(options.watch);
 
     let templates = templates::load(&options, reload_tx.clone())?;
+    let routes_table = routes::load(&options, reload_tx.clone())?;
 
-    log::info!("reading JSON value from stdin");
-    let value_rx = value::receiver(reload_tx.clone())?;
+    let (value_tx, value_rx) = value::channel(&options, reload_tx.clone())?;
+    let dependencies = PageDependencies::new(options.watch);
+    let cache = cache::RenderCache::new(&options.cache);
 
     server::run(
         &options.server,
-        warp::service(reload(reload_tx)
-            .or(render(templates, value_rx))
-            .or(warp::fs::dir(options.base))
-            .with(warp::log(module_path!()))),
+        warp::service(
+            api(&options.api, value_tx, cache.clone())
+                .or(reload(reload_tx, dependencies.clone()))
+                .or(server::compress(
+                    &options.server,
+                    render::conditional(inject_script(
+                        &options,
+                        routes(
+                            &options.render,
+                            routes_table,
+                            templates.clone(),
+                            value_rx.clone(),
+                            cache.clone(),
+                            dependencies.clone(),
+                        )
+                        .or(render(
+                            &options.render,
+                            templates.clone(),
+                            value_rx,
+                            cache,
+                            dependencies,
+                        ))
+                        .or(server::precompressed(&options.server, options.base.clone()))
+                        .or(warp::fs::dir(options.base.clone()))
+                        .or(render::not_found(&options.render, templates)),
+                    )),
+                ))
+                .with(warp::log(module_path!())),
+        ),
     )
     .await
 }
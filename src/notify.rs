@@ -8,7 +8,7 @@ use notify::Watcher;
 use tokio::time::delay_for;
 
 #[context("failed to watch filesystem at `{}`", path.display())]
-pub fn watch<F, R>(path: &Path, on_change: F) -> Result<()>
+pub fn watch<F, R>(path: &Path, mode: notify::RecursiveMode, on_change: F) -> Result<()>
 where
     F: Fn(Vec<notify::Event>) -> R + Send + Sync + 'static,
     R: Future<Output = ()> + Send,
@@ -17,7 +17,7 @@ where
     let poll_interval = Duration::from_millis(150);
 
     let mut watcher = notify::watcher(sender, poll_interval)?;
-    watcher.watch(path, notify::RecursiveMode::Recursive)?;
+    watcher.watch(path, mode)?;
 
     tokio::spawn(async move {
         let _watcher = watcher;
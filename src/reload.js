@@ -1,12 +1,254 @@
-const source = new EventSource("/sse");
-source.onmessage = message => {
-    if (message.data === "reload_value") {
+const page = location.pathname;
+const reloadScript = document.currentScript.src;
+
+const handlers = {
+    reload_value() {
         fetch(location.href)
             .then(response => response.text())
             .then(text => {
-                document.documentElement.innerHTML = text;
+                const updated = new DOMParser().parseFromString(text, "text/html");
+                morph(document.documentElement, updated.documentElement);
             });
-    } else if (message.data === "reload_page") {
+    },
+    // Reloading the page also clears an error from reloading the templates or routes.
+    reload_page() {
         location.reload();
+    },
+    reload_stylesheets({ paths }) {
+        const links = Array.from(document.querySelectorAll("link[rel=stylesheet]"))
+            .filter(link => paths.includes(new URL(link.href, location.href).pathname));
+        if (links.length === 0) {
+            // The stylesheets might be imported from elsewhere, so fall back to a full reload.
+            location.reload();
+            return;
+        }
+        for (const link of links) {
+            const url = new URL(link.href, location.href);
+            url.searchParams.set("reload", Date.now());
+            link.href = url.href;
+        }
+    },
+    reload_error({ error }) {
+        showError(error);
+    },
+    reload_resolved({ source }) {
+        hideError(source);
+    },
+};
+
+function handle(message) {
+    const handler = handlers[message.type];
+    if (handler) {
+        handler(message);
     }
-};
\ No newline at end of file
+}
+
+// Prefers a WebSocket, which also reports script errors to the server log, and falls back to
+// server-sent events if it can't be opened.
+function connectWebSocket() {
+    const protocol = location.protocol === "https:" ? "wss:" : "ws:";
+    const socket = new WebSocket(protocol + "//" + location.host + "/sse/ws");
+    let opened = false;
+
+    const reportError = event => {
+        socket.send(JSON.stringify({
+            type: "error",
+            message: event.message,
+            source: event.filename,
+            line: event.lineno,
+            column: event.colno,
+        }));
+    };
+
+    socket.onopen = () => {
+        opened = true;
+        socket.send(JSON.stringify({ type: "page", path: page }));
+        window.addEventListener("error", reportError);
+    };
+    socket.onmessage = message => handle(JSON.parse(message.data));
+    socket.onclose = () => {
+        window.removeEventListener("error", reportError);
+        if (opened) {
+            setTimeout(connectWebSocket, 1000);
+        } else {
+            connectEventSource();
+        }
+    };
+}
+
+function connectEventSource() {
+    const source = new EventSource("/sse?page=" + encodeURIComponent(page));
+    source.onmessage = message => handle({ type: message.data });
+    source.addEventListener("reload_stylesheets", message => {
+        handle({ type: "reload_stylesheets", paths: JSON.parse(message.data) });
+    });
+    source.addEventListener("reload_error", message => {
+        handle({ type: "reload_error", error: JSON.parse(message.data) });
+    });
+    source.addEventListener("reload_resolved", message => {
+        handle({ type: "reload_resolved", source: JSON.parse(message.data) });
+    });
+}
+
+if ("WebSocket" in window) {
+    connectWebSocket();
+} else {
+    connectEventSource();
+}
+
+const overlayId = "handlebars-server-error";
+
+function showError(error) {
+    hideError();
+
+    const overlay = document.createElement("div");
+    overlay.id = overlayId;
+    overlay.dataset.source = error.source;
+    overlay.style.cssText = "position: fixed; inset: 0; z-index: 2147483647; overflow: auto;"
+        + " background: rgba(0, 0, 0, 0.85); color: #e8e8e8; font: 14px monospace;";
+
+    const panel = document.createElement("div");
+    panel.style.cssText = "max-width: 960px; margin: 48px auto; padding: 24px;"
+        + " border-top: 4px solid #ff5555; background: #1e1e1e;";
+    overlay.appendChild(panel);
+
+    const close = document.createElement("button");
+    close.textContent = "×";
+    close.title = "Dismiss";
+    close.style.cssText = "float: right; border: none; background: none; color: inherit;"
+        + " font-size: 24px; cursor: pointer;";
+    close.onclick = () => hideError();
+    panel.appendChild(close);
+
+    const title = document.createElement("h1");
+    title.style.cssText = "margin-top: 0; color: #ff5555; font-size: 18px;";
+    title.textContent = "Failed to reload";
+    panel.appendChild(title);
+
+    if (error.template) {
+        const location = document.createElement("p");
+        location.textContent = "in template " + error.template
+            + (error.line ? " at line " + error.line + ", column " + error.column : "");
+        panel.appendChild(location);
+    }
+
+    const message = document.createElement("pre");
+    message.style.whiteSpace = "pre-wrap";
+    message.textContent = error.message;
+    panel.appendChild(message);
+
+    document.body.appendChild(overlay);
+}
+
+// Removes the error overlay, or only if the error came from reloading `source` if given.
+function hideError(source) {
+    const overlay = document.getElementById(overlayId);
+    if (overlay && (!source || overlay.dataset.source === source)) {
+        overlay.remove();
+    }
+}
+
+// Updates the live DOM in place to match `to`, so that unchanged elements keep their event
+// listeners, focus, scroll position and form state.
+function morph(from, to) {
+    if (from.nodeType !== Node.ELEMENT_NODE) {
+        if (from.nodeValue !== to.nodeValue) {
+            from.nodeValue = to.nodeValue;
+        }
+        return;
+    }
+
+    morphAttributes(from, to);
+    morphChildren(from, to);
+}
+
+function morphAttributes(from, to) {
+    for (const { name } of Array.from(from.attributes)) {
+        if (!to.hasAttribute(name)) {
+            from.removeAttribute(name);
+        }
+    }
+    for (const { name, value } of Array.from(to.attributes)) {
+        if (from.getAttribute(name) === value) {
+            continue;
+        }
+        from.setAttribute(name, value);
+        // Only override what the user entered if the page itself changed the value.
+        if (name === "value" && "value" in from) {
+            from.value = value;
+        } else if (name === "checked" && "checked" in from) {
+            from.checked = true;
+        }
+    }
+}
+
+function morphChildren(from, to) {
+    let current = from.firstChild;
+    for (const child of Array.from(to.childNodes)) {
+        const match = findMatch(current, child);
+        if (match) {
+            if (match !== current) {
+                from.insertBefore(match, current);
+            }
+            morph(match, child);
+            current = match.nextSibling;
+        } else {
+            from.insertBefore(importNode(child), current);
+        }
+    }
+
+    while (current) {
+        const next = current.nextSibling;
+        if (current.id !== overlayId) {
+            from.removeChild(current);
+        }
+        current = next;
+    }
+}
+
+// Imports a node from the parsed document. Scripts created by `DOMParser` never run, so they are
+// recreated to run when inserted, as they would on a page load.
+function importNode(node) {
+    const imported = document.importNode(node, true);
+    if (imported.nodeType !== Node.ELEMENT_NODE) {
+        return imported;
+    }
+
+    const recreate = script => {
+        const recreated = document.createElement("script");
+        for (const { name, value } of Array.from(script.attributes)) {
+            recreated.setAttribute(name, value);
+        }
+        recreated.text = script.text;
+        return recreated;
+    };
+    if (imported.nodeName === "SCRIPT") {
+        return imported.src === reloadScript ? imported : recreate(imported);
+    }
+    for (const script of Array.from(imported.querySelectorAll("script"))) {
+        // This script is already running.
+        if (script.src !== reloadScript) {
+            script.replaceWith(recreate(script));
+        }
+    }
+    return imported;
+}
+
+// Finds the node among `node` and its following siblings to morph into `to`. Elements with an
+// id are matched by id, and others only if they are at the current position.
+function findMatch(node, to) {
+    if (to.nodeType === Node.ELEMENT_NODE && to.id) {
+        for (let sibling = node; sibling; sibling = sibling.nextSibling) {
+            if (sibling.nodeName === to.nodeName && sibling.id === to.id) {
+                return sibling;
+            }
+        }
+        return null;
+    }
+
+    if (node && node.nodeType === to.nodeType && node.nodeName === to.nodeName
+        && !(node.nodeType === Node.ELEMENT_NODE && node.id)) {
+        return node;
+    }
+    return null;
+}
@@ -1,38 +1,239 @@
+use std::collections::HashSet;
 use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
 
-use futures::StreamExt as _;
-use http::header::{HeaderValue, CONTENT_TYPE};
+use bytes::Bytes;
+use futures::{stream, SinkExt as _, StreamExt as _};
+use http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use http::StatusCode;
+use hyper::body::HttpBody as _;
+use hyper::Body;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
 use tokio::sync::broadcast;
-use warp::Filter as _;
+use warp::reply::Response;
+use warp::sse::ServerSentEvent as _;
+use warp::{Filter as _, Reply};
 
-#[derive(Debug, Copy, Clone)]
+use crate::render::Streamed;
+use crate::templates::Templates;
+
+const SCRIPT_PATH: &str = "/sse/reload.js";
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(
+        long,
+        help = "Don't insert the live reload script into HTML pages when watching for changes"
+    )]
+    no_reload_script: bool,
+}
+
+#[derive(Debug, Clone)]
 pub enum ReloadKind {
+    /// The value has changed at these JSON pointers.
+    Value(Vec<String>),
+    /// These templates have changed.
+    Templates(Vec<String>),
+    Page,
+    /// Only the stylesheets at these URL paths have changed.
+    Stylesheets(Vec<String>),
+    /// Reloading failed, so the browser shows the error until what failed is reloaded.
+    Error(ReloadError),
+    /// What failed to reload before has been reloaded successfully.
+    Resolved(ReloadSource),
+}
+
+/// What failed to reload, so the error is only cleared by reloading the same thing.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadSource {
+    Templates,
+    Routes,
+    Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadError {
+    pub source: ReloadSource,
+    pub message: String,
+    pub template: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl ReloadError {
+    /// Describes `err`, taking the location from the first template error in its chain.
+    pub fn new(source: ReloadSource, err: &anyhow::Error) -> Self {
+        let template_error = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<handlebars::TemplateError>());
+        ReloadError {
+            source,
+            message: format!("{:#}", err),
+            template: template_error.and_then(|err| err.template_name.clone()),
+            line: template_error.and_then(|err| err.line_no),
+            column: template_error.and_then(|err| err.column_no),
+        }
+    }
+}
+
+/// What a rendered page depends on, so clients only reload when it is affected by a change.
+#[derive(Debug, Clone)]
+struct Dependencies {
+    /// The names of the templates used, or `None` if they are only known at render time.
+    templates: Option<HashSet<String>>,
+    /// The JSON pointer of the value the page was rendered against.
+    pointer: String,
+}
+
+impl Dependencies {
+    fn affected_by(&self, kind: &ReloadKind) -> bool {
+        match kind {
+            ReloadKind::Value(pointers) => pointers.iter().any(|pointer| {
+                contains(pointer, &self.pointer) || contains(&self.pointer, pointer)
+            }),
+            ReloadKind::Templates(names) => match &self.templates {
+                Some(templates) => names.iter().any(|name| templates.contains(name)),
+                None => true,
+            },
+            _ => true,
+        }
+    }
+}
+
+/// Returns whether the value at `pointer` contains the value at `other`.
+fn contains(pointer: &str, other: &str) -> bool {
+    match other.strip_prefix(pointer) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// The number of pages to keep the dependencies of. Clients showing pages which were rendered
+/// longer ago are sent every change.
+const MAX_PAGES: usize = 1024;
+
+/// The dependencies of the most recently rendered pages by URL path, only kept when watching for
+/// changes.
+#[derive(Clone)]
+pub struct PageDependencies(Option<Arc<Mutex<LruCache<String, Dependencies>>>>);
+
+impl PageDependencies {
+    pub fn new(watch: bool) -> Self {
+        PageDependencies(if watch {
+            Some(Arc::new(Mutex::new(LruCache::new(MAX_PAGES))))
+        } else {
+            None
+        })
+    }
+
+    /// Records that the page at the URL `path` was rendered with the template `name` against the
+    /// value at `pointer`.
+    pub fn record(&self, path: &str, templates: &Templates, name: &str, pointer: &str) {
+        let pages = match &self.0 {
+            Some(pages) => pages,
+            None => return,
+        };
+        let dependencies = Dependencies {
+            templates: templates.dependencies(name),
+            pointer: pointer.to_owned(),
+        };
+        pages.lock().unwrap().put(path.to_owned(), dependencies);
+    }
+
+    /// Returns whether the page at `path` is affected by `kind`. Pages without recorded
+    /// dependencies, like static files, are affected by every change.
+    fn is_affected(&self, path: &str, kind: &ReloadKind) -> bool {
+        let pages = match &self.0 {
+            Some(pages) => pages,
+            None => return true,
+        };
+        let mut pages = pages.lock().unwrap();
+        pages
+            .get(&path.to_owned())
+            .is_none_or(|dependencies| dependencies.affected_by(kind))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PageQuery {
+    /// The URL path of the page the client is showing.
+    page: Option<String>,
+}
+
+/// A reload event sent over the WebSocket.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+enum ServerMessage<'a> {
+    #[serde(rename = "reload_value")]
     Value,
+    #[serde(rename = "reload_page")]
     Page,
+    #[serde(rename = "reload_stylesheets")]
+    Stylesheets { paths: &'a [String] },
+    #[serde(rename = "reload_error")]
+    Error { error: &'a ReloadError },
+    #[serde(rename = "reload_resolved")]
+    Resolved { source: ReloadSource },
+}
+
+/// A message sent by the client over the WebSocket.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// The client is showing the page at the URL `path`.
+    Page { path: String },
+    /// A script error occurred in the client.
+    Error {
+        message: String,
+        source: Option<String>,
+        line: Option<u32>,
+        column: Option<u32>,
+    },
 }
 
 pub fn reload(
     reload_tx: broadcast::Sender<ReloadKind>,
+    dependencies: PageDependencies,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let ws_reload_tx = reload_tx.clone();
+    let ws_dependencies = dependencies.clone();
+    let ws = warp::path!("ws")
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| {
+            let events = ws_reload_tx.subscribe();
+            let dependencies = ws_dependencies.clone();
+            ws.on_upgrade(move |socket| websocket(socket, events, dependencies))
+        });
+
     warp::path::path("sse").and(warp::get()).and(
         warp::path::end()
-            .map(move || {
+            .and(
+                warp::query::<PageQuery>()
+                    .or(warp::any().map(PageQuery::default))
+                    .unify(),
+            )
+            .map(move |query: PageQuery| {
+                let dependencies = dependencies.clone();
                 warp::sse::reply(
                     warp::sse::keep_alive().stream(
                         reload_tx
                             .subscribe()
                             .filter_map(|kind| async { kind.ok() })
-                            .map(|kind| {
-                                let data = match kind {
-                                    ReloadKind::Value => "reload_value",
-                                    ReloadKind::Page => "reload_page",
+                            .filter(move |kind| {
+                                let affected = match &query.page {
+                                    Some(page) => dependencies.is_affected(page, kind),
+                                    None => true,
                                 };
-                                log::info!("sending '{}' event", data);
-                                Result::<_, Infallible>::Ok(warp::sse::data(data))
-                            }),
+                                async move { affected }
+                            })
+                            .map(|kind| Result::<_, Infallible>::Ok(to_event(kind))),
                     ),
                 )
             })
+            .or(ws)
             .or(warp::path!("reload.js").map(|| {
                 warp::reply::with_header(
                     include_str!("reload.js"),
@@ -42,3 +243,223 @@ pub fn reload(
             })),
     )
 }
+
+/// Sends reload events affecting the client's page over `socket`, and logs errors reported by
+/// the client.
+async fn websocket(
+    socket: warp::ws::WebSocket,
+    mut events: broadcast::Receiver<ReloadKind>,
+    dependencies: PageDependencies,
+) {
+    let (mut socket_tx, mut socket_rx) = socket.split();
+    let mut page = None;
+    loop {
+        tokio::select! {
+            message = socket_rx.next() => match message {
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(message)) => {
+                    let message = match message.to_str() {
+                        Ok(message) => message,
+                        Err(()) => continue,
+                    };
+                    match serde_json::from_str(message) {
+                        Ok(ClientMessage::Page { path }) => page = Some(path),
+                        Ok(ClientMessage::Error { message, source, line, column }) => log::warn!(
+                            "client error on page `{}`: {} ({}:{}:{})",
+                            page.as_deref().unwrap_or_default(),
+                            message,
+                            source.as_deref().unwrap_or_default(),
+                            line.unwrap_or_default(),
+                            column.unwrap_or_default()
+                        ),
+                        Err(err) => log::warn!("invalid WebSocket message: {}", err),
+                    }
+                }
+                Some(Err(err)) => {
+                    log::debug!("WebSocket error: {}", err);
+                    break;
+                }
+                None => break,
+            },
+            kind = events.next() => {
+                let kind = match kind {
+                    Some(Ok(kind)) => kind,
+                    Some(Err(_)) => continue,
+                    None => break,
+                };
+                let affected = match &page {
+                    Some(page) => dependencies.is_affected(page, &kind),
+                    None => true,
+                };
+                if !affected {
+                    continue;
+                }
+                let message = serde_json::to_string(&to_message(&kind)).unwrap();
+                if socket_tx.send(warp::ws::Message::text(message)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn to_message(kind: &ReloadKind) -> ServerMessage<'_> {
+    let message = match kind {
+        ReloadKind::Value(_) => ServerMessage::Value,
+        ReloadKind::Templates(_) | ReloadKind::Page => ServerMessage::Page,
+        ReloadKind::Stylesheets(paths) => ServerMessage::Stylesheets { paths },
+        ReloadKind::Error(error) => ServerMessage::Error { error },
+        ReloadKind::Resolved(source) => ServerMessage::Resolved { source: *source },
+    };
+    log::info!("sending {:?} message", message);
+    message
+}
+
+/// Converts `kind` to an SSE event. Reloads without details are sent as unnamed events with the
+/// kind as data, and others as named events with JSON data.
+fn to_event(kind: ReloadKind) -> impl warp::sse::ServerSentEvent {
+    let (name, data) = match kind {
+        ReloadKind::Value(_) => {
+            log::info!("sending 'reload_value' event");
+            return warp::sse::data("reload_value").into_a();
+        }
+        ReloadKind::Templates(_) | ReloadKind::Page => {
+            log::info!("sending 'reload_page' event");
+            return warp::sse::data("reload_page").into_a();
+        }
+        ReloadKind::Stylesheets(paths) => ("reload_stylesheets", serde_json::json!(paths)),
+        ReloadKind::Error(error) => ("reload_error", serde_json::json!(error)),
+        ReloadKind::Resolved(source) => ("reload_resolved", serde_json::json!(source)),
+    };
+    log::info!("sending '{}' event", name);
+    (warp::sse::event(name), warp::sse::json(data)).into_b()
+}
+
+/// Inserts a script tag for `reload.js` into HTML responses from `filter`, if watching for changes
+/// and not disabled in `options`.
+pub fn inject_script<F, R>(
+    options: &crate::Options,
+    filter: F,
+) -> impl warp::Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+where
+    F: warp::Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let enabled = options.watch && !options.reload.no_reload_script;
+    filter
+        .map(R::into_response)
+        .and_then(move |response| async move {
+            if enabled {
+                Ok::<_, Infallible>(insert_script_tag(response).await)
+            } else {
+                Ok(response)
+            }
+        })
+}
+
+async fn insert_script_tag(response: Response) -> Response {
+    let is_html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    let status = response.status();
+    if !is_html
+        || response.headers().contains_key(CONTENT_ENCODING)
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return response;
+    }
+
+    let tag = format!("<script src=\"{}\"></script>", SCRIPT_PATH);
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    if parts.extensions.get::<Streamed>().is_some() {
+        return Response::from_parts(parts, append_script_tag(body, tag));
+    }
+
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            log::error!("failed to read response body: {}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut html = match std::str::from_utf8(&body) {
+        Ok(html) if !html.contains(SCRIPT_PATH) => html.to_owned(),
+        _ => return Response::from_parts(parts, body.into()),
+    };
+
+    match html.to_ascii_lowercase().rfind("</body>") {
+        Some(idx) => html.insert_str(idx, &tag),
+        None => html.push_str(&tag),
+    }
+
+    Response::from_parts(parts, html.into())
+}
+
+/// Passes on a page streamed as it is rendered, then appends `tag` instead of waiting for the whole
+/// page, unless the page included the script itself. Browsers move the tag into the body.
+fn append_script_tag(body: Body, tag: String) -> Body {
+    // The end of the output so far, which could contain the start of the script path, or `None`
+    // once the script path was found.
+    let chunks = stream::unfold(Some((body, Some(Vec::new()))), move |state| {
+        let tag = tag.clone();
+        async move {
+            let (mut body, tail) = state?;
+            match body.data().await {
+                Some(Ok(chunk)) => {
+                    let tail = tail.and_then(|mut tail| {
+                        tail.extend_from_slice(&chunk);
+                        if tail
+                            .windows(SCRIPT_PATH.len())
+                            .any(|window| window == SCRIPT_PATH.as_bytes())
+                        {
+                            return None;
+                        }
+                        tail.drain(..tail.len().saturating_sub(SCRIPT_PATH.len() - 1));
+                        Some(tail)
+                    });
+                    Some((Ok(chunk), Some((body, tail))))
+                }
+                Some(Err(err)) => Some((Err(err), None)),
+                None => tail.map(|_| (Ok(Bytes::from(tag)), None)),
+            }
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn append(chunks: &[&'static str]) -> String {
+        let chunks: Vec<_> = chunks
+            .iter()
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::from(*chunk)))
+            .collect();
+        let body = append_script_tag(Body::wrap_stream(stream::iter(chunks)), "<tag>".into());
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn append_script_tag_once() {
+        assert_eq!(append(&["<p>a", "</p>"]).await, "<p>a</p><tag>");
+        assert_eq!(
+            append(&["<script src=\"/sse/reload.js\">", "</script>"]).await,
+            "<script src=\"/sse/reload.js\"></script>"
+        );
+        assert_eq!(
+            append(&["<script src=\"/sse/rel", "oad.js\"></script>"]).await,
+            "<script src=\"/sse/reload.js\"></script>"
+        );
+        assert_eq!(
+            append(&["/sse/rel", "x", "oad.js"]).await,
+            "/sse/relxoad.js<tag>"
+        );
+    }
+}
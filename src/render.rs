@@ -1,63 +1,654 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash as _, Hasher as _};
+use std::io;
+use std::mem;
 use std::sync::Arc;
 
-use handlebars::Handlebars;
+use bytes::Bytes;
+
+use chrono::{DateTime, Utc};
+use handlebars::{Handlebars, Output, RenderError};
+use http::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, ETAG, LAST_MODIFIED, LOCATION, VARY};
+use http::StatusCode;
+use hyper::Body;
+use mime_guess::mime::Mime;
+use serde::Serialize;
 use serde_json::Value;
+use structopt::StructOpt;
 use tokio::sync::watch::Receiver;
 use tokio::sync::RwLock;
 use warp::{Filter as _, Reply as _};
 
+use crate::cache::{self, RenderCache};
+use crate::helpers;
+use crate::reload::PageDependencies;
+use crate::templates::Templates;
+use crate::value::Versioned;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Options {
+    #[structopt(
+        long,
+        help = "Serve pages like `page.hbs` at `page` instead of `page.html`, redirecting from the `.html` URL"
+    )]
+    clean_urls: bool,
+    #[structopt(
+        long,
+        help = "Show template errors with their location in the browser instead of the `500` page"
+    )]
+    dev: bool,
+    #[structopt(
+        long,
+        help = "Minify rendered HTML pages, including inline CSS and JavaScript. Ignored with `--watch`"
+    )]
+    minify: bool,
+}
+
+impl Options {
+    /// Turns off minification while watching for changes, so pages stay readable for debugging.
+    pub fn disable_minify_when_watching(&mut self, watch: bool) {
+        if watch && self.minify {
+            log::info!("not minifying pages while watching for changes");
+            self.minify = false;
+        }
+    }
+}
+
+/// Why a page could not be rendered.
+pub enum PageError {
+    /// There is no value at the pointer.
+    NotFound(String),
+    Render(RenderError),
+}
+
+/// Describes a failed request, passed as the value to the `404` and `500` templates.
+#[derive(Debug, Serialize)]
+pub struct ErrorContext {
+    pub status: u16,
+    pub message: String,
+    pub path: String,
+    pub template: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl ErrorContext {
+    pub fn not_found(path: &str, message: String) -> Self {
+        ErrorContext {
+            status: StatusCode::NOT_FOUND.as_u16(),
+            message,
+            path: path.to_owned(),
+            template: None,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+/// The page rendered for URL paths ending in `/`.
+const INDEX: &str = "index";
+
 pub fn render(
-    templates: Arc<RwLock<Handlebars<'static>>>,
-    value_rx: Receiver<Value>,
+    options: &Options,
+    templates: Arc<RwLock<Arc<Templates>>>,
+    value_rx: Receiver<Versioned>,
+    cache: RenderCache,
+    dependencies: PageDependencies,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let options = options.clone();
     warp::get()
         .and(warp::path::full())
-        .and_then(move |path: warp::path::FullPath| {
+        .and(
+            warp::query::raw()
+                .map(|query| format!("?{}", query))
+                .or(warp::any().map(String::new))
+                .unify(),
+        )
+        .and_then(move |full_path: warp::path::FullPath, query: String| {
+            let options = options.clone();
             let templates = templates.clone();
             let value_rx = value_rx.clone();
+            let cache = cache.clone();
+            let dependencies = dependencies.clone();
             async move {
-                let path = match urlencoding::decode(path.as_str()) {
+                let path = match urlencoding::decode(full_path.as_str()) {
                     Ok(path) => path,
                     Err(_) => return Err(warp::reject::not_found()),
                 };
-                let (path, file) = match rsplit2(&path, "/") {
+                let (path, file) = match path.rsplit_once('/') {
                     Some(split) => split,
                     None => return Err(warp::reject::not_found()),
                 };
-
-                let templates = templates.read().await;
-
-                let name = match rsplit2(file, ".") {
-                    Some((name, "html")) if templates.has_template(name) => name,
-                    Some((_, "hbs")) => return Ok(http::StatusCode::NOT_FOUND.into_response()),
-                    _ => return Err(warp::reject::not_found()),
+                let redirect_to_file = |file: &str| {
+                    let (dir, _) = full_path.as_str().rsplit_once('/').unwrap();
+                    let location = format!("{}/{}{}", dir, urlencoding::encode(file), query);
+                    Ok(redirect(&location))
                 };
 
-                let value = value_rx.borrow();
-                let subvalue = match value.pointer(path) {
-                    Some(subvalue) => subvalue,
-                    None => {
-                        log::warn!("pointer error: {}", path);
-                        return Ok(http::StatusCode::NOT_FOUND.into_response());
-                    }
-                };
+                // Render without holding the lock or borrowing the value, so reloads and value
+                // updates aren't blocked meanwhile.
+                let templates = templates.read().await.clone();
+                let value = value_rx.borrow().clone();
 
-                let result = match templates.render(name, subvalue) {
-                    Ok(result) => result,
-                    Err(err) => {
-                        log::error!("template error: {}", err);
-                        return Ok(http::StatusCode::NOT_FOUND.into_response());
+                let name = if file.is_empty() {
+                    if !templates.is_page(INDEX) {
+                        return Err(warp::reject::not_found());
+                    }
+                    INDEX
+                } else if let Some(name) = templates.page_for_file(file) {
+                    if name == INDEX {
+                        return redirect_to_file("");
+                    } else if options.clean_urls && file != name {
+                        return redirect_to_file(name);
+                    }
+                    name
+                } else if !file.contains('.') {
+                    if options.clean_urls && templates.is_page(file) {
+                        if file == INDEX {
+                            return redirect_to_file("");
+                        }
+                        file
+                    } else if templates.is_page(INDEX)
+                        && value
+                            .value
+                            .pointer(&page_pointer(&templates, INDEX, path, file))
+                            .is_some()
+                    {
+                        let location = format!("{}/{}", full_path.as_str(), query);
+                        return Ok(redirect(&location));
+                    } else {
+                        return Err(warp::reject::not_found());
                     }
+                } else if file.ends_with(".hbs") || file.ends_with(".rhai") {
+                    let context = ErrorContext::not_found(full_path.as_str(), "Not Found".into());
+                    return Ok(error_page(&options, &templates, context));
+                } else {
+                    return Err(warp::reject::not_found());
                 };
 
-                Ok(warp::reply::html(result).into_response())
+                let pointer = page_pointer(&templates, name, path, "");
+                Ok(render_page(
+                    &options,
+                    &templates,
+                    &cache,
+                    name,
+                    &pointer,
+                    &Value::Null,
+                    &value,
+                )
+                .inspect(|_| dependencies.record(full_path.as_str(), &templates, name, &pointer))
+                .unwrap_or_else(|err| page_error(&options, &templates, full_path.as_str(), err)))
+            }
+        })
+}
+
+/// Returns the pointer a page is rendered against for the directory `path` of a URL, optionally
+/// followed by a `segment`, relative to the `data` pointer in its front matter.
+fn page_pointer(templates: &Templates, name: &str, path: &str, segment: &str) -> String {
+    let data = templates.front_matter(name).data.as_deref().unwrap_or("");
+    if segment.is_empty() {
+        format!("{}{}", data, path)
+    } else {
+        format!("{}{}/{}", data, path, segment.replace('~', "~0"))
+    }
+}
+
+/// Redirects to the canonical URL of a page, permanently so clients and search engines use it
+/// from then on.
+fn redirect(location: &str) -> warp::reply::Response {
+    match HeaderValue::from_str(location) {
+        Ok(location) => {
+            warp::reply::with_header(StatusCode::PERMANENT_REDIRECT, LOCATION, location)
+                .into_response()
+        }
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Renders the page `name` against the value at `pointer`, applying the status code and content
+/// type from its front matter. `params` are available to the template through the `params`
+/// helper. The output is minified if enabled, and cached until the templates or the value change
+/// unless it uses a helper such as `now`.
+///
+/// Responses have a strong `ETag` computed from the output and a `Last-Modified` date from when the
+/// templates or the value last changed, so clients can revalidate them with conditional requests.
+pub fn render_page(
+    options: &Options,
+    templates: &Arc<Templates>,
+    cache: &RenderCache,
+    name: &str,
+    pointer: &str,
+    params: &Value,
+    value: &Versioned,
+) -> Result<warp::reply::Response, PageError> {
+    let subvalue = value
+        .value
+        .pointer(pointer)
+        .ok_or_else(|| PageError::NotFound(pointer.to_owned()))?;
+
+    let front_matter = templates.front_matter(name);
+    let mut volatile = false;
+    let mut response = if front_matter.stream {
+        let mut response = warp::reply::Response::new(stream_page(
+            templates.clone(),
+            name,
+            pointer,
+            params,
+            value.value.clone(),
+        ));
+        response.extensions_mut().insert(Streamed);
+        response
+    } else {
+        let key = cache::Key {
+            name: name.to_owned(),
+            pointer: pointer.to_owned(),
+            params: params.to_string(),
+            generation: templates.generation(),
+            version: value.version,
+        };
+        let (result, cacheable) = cache
+            .get_or_render(key, || {
+                let (output, volatile) =
+                    helpers::track_volatile(|| templates.render(name, subvalue, params));
+                Ok((minify(options, templates, name, output?), !volatile))
+            })
+            .map_err(PageError::Render)?;
+        volatile = !cacheable;
+
+        let mut response = warp::reply::Response::new(result.to_string().into());
+        response.headers_mut().insert(ETAG, etag(&result));
+        response
+    };
+    if let Some(status) = front_matter.status {
+        *response.status_mut() = status;
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, page_content_type(templates, name));
+    // Output depending on the time it was rendered wasn't last modified when its inputs were.
+    if !volatile {
+        headers.insert(
+            LAST_MODIFIED,
+            http_date(templates.modified().max(value.modified)),
+        );
+    }
+    headers.insert(
+        CACHE_CONTROL,
+        front_matter
+            .cache_control
+            .clone()
+            .unwrap_or_else(|| HeaderValue::from_static("no-cache")),
+    );
+    Ok(response)
+}
+
+/// Marks the responses of pages which are sent as they are rendered.
+pub struct Streamed;
+
+/// Renders the page `name` on a blocking thread, returning a body which receives the output as
+/// it is written.
+fn stream_page(
+    templates: Arc<Templates>,
+    name: &str,
+    pointer: &str,
+    params: &Value,
+    value: Arc<Value>,
+) -> Body {
+    let (sender, body) = Body::channel();
+    let name = name.to_owned();
+    let pointer = pointer.to_owned();
+    let params = params.clone();
+    tokio::task::spawn_blocking(move || {
+        let subvalue = value.pointer(&pointer).unwrap_or(&Value::Null);
+        let mut output = BodyOutput {
+            sender,
+            buffer: Vec::with_capacity(STREAM_CHUNK_SIZE),
+            disconnected: false,
+        };
+        let result = templates
+            .render_to(&name, subvalue, &params, &mut output)
+            .and_then(|()| output.flush().map_err(RenderError::from));
+        match result {
+            Ok(()) => {}
+            Err(_) if output.disconnected => {
+                log::debug!("client disconnected while streaming page `{}`", name);
+            }
+            Err(err) => {
+                log::error!("failed to render streamed page `{}`: {}", name, err);
+                // Abort the response so the client does not mistake it for a complete page.
+                output.sender.abort();
             }
+        }
+    });
+    body
+}
+
+/// The size of the chunks streamed pages are sent in.
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Sends rendered output to a response body in chunks, blocking while the client catches up.
+struct BodyOutput {
+    sender: hyper::body::Sender,
+    buffer: Vec<u8>,
+    disconnected: bool,
+}
+
+impl BodyOutput {
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(STREAM_CHUNK_SIZE),
+        ));
+        futures::executor::block_on(self.sender.send_data(chunk)).map_err(|err| {
+            self.disconnected = true;
+            io::Error::new(io::ErrorKind::BrokenPipe, err)
         })
+    }
+}
+
+impl Output for BodyOutput {
+    fn write(&mut self, seg: &str) -> io::Result<()> {
+        self.buffer.extend_from_slice(seg.as_bytes());
+        if self.buffer.len() >= STREAM_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn etag(body: &str) -> HeaderValue {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    HeaderValue::from_str(&format!("\"{:016x}\"", hasher.finish())).unwrap()
+}
+
+pub fn http_date(date: DateTime<Utc>) -> HeaderValue {
+    HeaderValue::from_str(&date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).unwrap()
+}
+
+/// Responds with `304 Not Modified` when the `If-None-Match` or `If-Modified-Since` headers of a
+/// request show the client already has the response of `filter`.
+pub fn conditional<F, R>(
+    filter: F,
+) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    F: warp::Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    warp::header::optional::<String>("if-none-match")
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(filter)
+        .map(
+            |if_none_match: Option<String>, if_modified_since: Option<String>, reply: R| {
+                let response = reply.into_response();
+                if response.status() != StatusCode::OK
+                    || !is_not_modified(&response, if_none_match, if_modified_since)
+                {
+                    return response;
+                }
+
+                let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+                for name in &[ETAG, LAST_MODIFIED, CACHE_CONTROL, VARY] {
+                    if let Some(value) = response.headers().get(name) {
+                        not_modified.headers_mut().insert(name, value.clone());
+                    }
+                }
+                not_modified
+            },
+        )
+}
+
+/// Evaluates the preconditions as described in RFC 7232, where `If-Modified-Since` is ignored if
+/// `If-None-Match` is present.
+fn is_not_modified(
+    response: &warp::reply::Response,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+) -> bool {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+
+    if let Some(if_none_match) = if_none_match {
+        let etag = header(ETAG).map(|etag| etag.trim_start_matches("W/"));
+        return if_none_match.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || Some(candidate.trim_start_matches("W/")) == etag
+        });
+    }
+
+    let last_modified =
+        header(LAST_MODIFIED).and_then(|date| DateTime::parse_from_rfc2822(date).ok());
+    let if_modified_since =
+        if_modified_since.and_then(|date| DateTime::parse_from_rfc2822(&date).ok());
+    match (last_modified, if_modified_since) {
+        (Some(last_modified), Some(if_modified_since)) => last_modified <= if_modified_since,
+        _ => false,
+    }
+}
+
+/// Logs a failure to render the page at the URL `path` and responds with an error page.
+pub fn page_error(
+    options: &Options,
+    templates: &Templates,
+    path: &str,
+    error: PageError,
+) -> warp::reply::Response {
+    let context = match error {
+        PageError::NotFound(pointer) => {
+            log::warn!("pointer error: {}", pointer);
+            ErrorContext::not_found(path, format!("No value at `{}`", pointer))
+        }
+        // The value at a pointer from the URL doesn't have what the template needs, so it is
+        // treated like a missing value.
+        PageError::Render(err) if is_missing_field(&err) => {
+            log::warn!("template error: {}", err);
+            ErrorContext::not_found(path, err.desc)
+        }
+        PageError::Render(err) => {
+            log::error!("template error: {}", err);
+            ErrorContext {
+                status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                message: err.desc,
+                path: path.to_owned(),
+                template: err.template_name,
+                line: err.line_no,
+                column: err.column_no,
+            }
+        }
+    };
+    error_page(options, templates, context)
+}
+
+/// Returns whether rendering failed because strict mode found a field missing from the value.
+fn is_missing_field(err: &RenderError) -> bool {
+    err.desc.ends_with("not found in strict mode.") || err.desc == "Value is missing in strict mode"
+}
+
+/// Renders the `404` or `500` template for the status of `context`, falling back to an empty
+/// response if there is no such template. In developer mode, server errors are shown in a
+/// built-in overlay instead.
+pub fn error_page(
+    options: &Options,
+    templates: &Templates,
+    context: ErrorContext,
+) -> warp::reply::Response {
+    let status = StatusCode::from_u16(context.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    if options.dev && status.is_server_error() {
+        return dev_overlay(status, &context);
+    }
+
+    let name = status.as_str();
+    if !templates.has_template(name) {
+        return status.into_response();
+    }
+
+    let value = serde_json::to_value(&context).unwrap();
+    match templates.render(name, &value, &Value::Null) {
+        Ok(html) => warp::reply::with_status(warp::reply::html(html), status).into_response(),
+        Err(err) => {
+            log::error!("failed to render error page `{}`: {}", name, err);
+            if options.dev {
+                return dev_overlay(status, &context);
+            }
+            status.into_response()
+        }
+    }
 }
 
-fn rsplit2<'a>(string: &'a str, pat: &str) -> Option<(&'a str, &'a str)> {
-    let mut iter = string.rsplitn(2, pat);
-    let last = iter.next()?;
-    let init = iter.next()?;
-    Some((init, last))
+fn dev_overlay(status: StatusCode, context: &ErrorContext) -> warp::reply::Response {
+    match Handlebars::new().render_template(include_str!("error.html"), context) {
+        Ok(html) => warp::reply::with_status(warp::reply::html(html), status).into_response(),
+        Err(err) => {
+            log::error!("failed to render error overlay: {}", err);
+            status.into_response()
+        }
+    }
+}
+
+/// Responds to any request not handled by another filter with the `404` page, if there is one.
+pub fn not_found(
+    options: &Options,
+    templates: Arc<RwLock<Arc<Templates>>>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let options = options.clone();
+    warp::path::full().and_then(move |path: warp::path::FullPath| {
+        let options = options.clone();
+        let templates = templates.clone();
+        async move {
+            let templates = templates.read().await.clone();
+            if !templates.has_template(StatusCode::NOT_FOUND.as_str()) {
+                return Err(warp::reject::not_found());
+            }
+            let context = ErrorContext::not_found(path.as_str(), "Not Found".into());
+            Ok(error_page(&options, &templates, context))
+        }
+    })
+}
+
+/// Minifies the output of the page `name` if `--minify` is set and the page is HTML.
+pub fn minify(options: &Options, templates: &Templates, name: &str, output: String) -> String {
+    let is_html = page_content_type(templates, name)
+        .to_str()
+        .is_ok_and(|content_type| content_type.starts_with("text/html"));
+    if !options.minify || !is_html {
+        return output;
+    }
+
+    // Closing tags are kept so the output is still easy to process as text, for example when
+    // inserting the reload script before `</body>`.
+    let cfg = minify_html::Cfg {
+        keep_closing_tags: true,
+        keep_html_and_head_opening_tags: true,
+        minify_css: true,
+        minify_js: true,
+        ..minify_html::Cfg::spec_compliant()
+    };
+    match String::from_utf8(minify_html::minify(output.as_bytes(), &cfg)) {
+        Ok(minified) => minified,
+        Err(_) => output,
+    }
+}
+
+/// Returns the content type of the page `name`, from its front matter or its file name.
+fn page_content_type(templates: &Templates, name: &str) -> HeaderValue {
+    match &templates.front_matter(name).content_type {
+        Some(content_type) => content_type.clone(),
+        None => content_type(&templates.file_name(name)),
+    }
+}
+
+/// Guesses the content type of a rendered page from its file name, assuming UTF-8 for text.
+fn content_type(file: &str) -> HeaderValue {
+    let mime = mime_guess::from_path(file).first_or_octet_stream();
+    let content_type = if is_text(&mime) {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    };
+    HeaderValue::from_str(&content_type).unwrap()
+}
+
+/// Returns whether `mime` is a textual format, like HTML, CSS, JSON or SVG.
+pub fn is_text(mime: &Mime) -> bool {
+    mime.type_() == mime_guess::mime::TEXT
+        || ["json", "xml", "javascript"].contains(&mime.subtype().as_str())
+        || mime
+            .suffix()
+            .is_some_and(|suffix| suffix == "xml" || suffix == "json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn response() -> warp::reply::Response {
+        let mut response = warp::reply::Response::new("page".into());
+        response.headers_mut().insert(ETAG, etag("page"));
+        response.headers_mut().insert(
+            LAST_MODIFIED,
+            http_date(Utc.with_ymd_and_hms(2020, 12, 25, 12, 0, 0).unwrap()),
+        );
+        response
+    }
+
+    fn not_modified(if_none_match: Option<&str>, if_modified_since: Option<&str>) -> bool {
+        is_not_modified(
+            &response(),
+            if_none_match.map(str::to_owned),
+            if_modified_since.map(str::to_owned),
+        )
+    }
+
+    #[test]
+    fn if_none_match() {
+        let etag = etag("page");
+        let etag = etag.to_str().unwrap();
+        assert!(not_modified(Some(etag), None));
+        assert!(not_modified(Some(&format!("W/{}", etag)), None));
+        assert!(not_modified(Some(&format!("\"other\", {}", etag)), None));
+        assert!(not_modified(Some("*"), None));
+        assert!(!not_modified(Some("\"other\""), None));
+
+        // Streamed pages have no `ETag`.
+        let response = warp::reply::Response::new("page".into());
+        assert!(is_not_modified(&response, Some("*".to_owned()), None));
+        assert!(!is_not_modified(&response, Some(etag.to_owned()), None));
+    }
+
+    #[test]
+    fn missing_field() {
+        let mut handlebars = handlebars::Handlebars::new();
+        handlebars.set_strict_mode(true);
+        crate::helpers::register(&mut handlebars);
+        let render = |template| {
+            handlebars
+                .render_template(template, &serde_json::json!({"a": {}}))
+                .unwrap_err()
+        };
+        assert!(is_missing_field(&render("{{b}}")));
+        assert!(is_missing_field(&render("{{a.b}}")));
+        assert!(is_missing_field(&render("{{upper b}}")));
+        assert!(!is_missing_field(&render("{{upper a}}")));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        assert!(not_modified(None, Some("Fri, 25 Dec 2020 12:00:00 GMT")));
+        assert!(not_modified(None, Some("Sat, 26 Dec 2020 00:00:00 GMT")));
+        assert!(!not_modified(None, Some("Fri, 25 Dec 2020 11:59:59 GMT")));
+        assert!(!not_modified(None, Some("yesterday")));
+        // `If-Modified-Since` is ignored when `If-None-Match` is present.
+        assert!(!not_modified(
+            Some("\"other\""),
+            Some("Sat, 26 Dec 2020 00:00:00 GMT")
+        ));
+    }
 }
@@ -0,0 +1,357 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use fn_error_context::context;
+use http::header::{HeaderValue, CACHE_CONTROL};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use structopt::StructOpt;
+use tokio::sync::watch::Receiver;
+use tokio::sync::{broadcast, RwLock};
+use warp::Filter as _;
+
+use crate::cache::RenderCache;
+use crate::reload::{PageDependencies, ReloadError, ReloadKind, ReloadSource};
+use crate::render::{self, page_error, render_page};
+use crate::templates::Templates;
+use crate::value::Versioned;
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(
+        name = "routes",
+        long,
+        value_name = "FILE",
+        help = "Read a table of routes mapping URL patterns like `/blog/{slug}` to templates and pointers from a JSON, YAML or TOML file",
+        parse(from_os_str)
+    )]
+    path: Option<PathBuf>,
+}
+
+impl Options {
+    /// Returns the canonical path of the routes file, if any.
+    pub fn file(&self) -> Option<PathBuf> {
+        self.path.as_ref()?.canonicalize().ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteConfig {
+    path: String,
+    template: String,
+    data: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "crate::templates::deserialize_header_value"
+    )]
+    cache_control: Option<HeaderValue>,
+}
+
+#[derive(Debug, Default)]
+pub struct Routes(Vec<Route>);
+
+#[derive(Debug)]
+struct Route {
+    segments: Vec<Segment>,
+    template: String,
+    /// JSON pointer to render the template against, which may contain `{param}` placeholders.
+    data: Option<String>,
+    /// The `Cache-Control` header of responses, overriding the template's front matter.
+    cache_control: Option<HeaderValue>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+pub fn load(
+    options: &crate::Options,
+    reload_tx: broadcast::Sender<ReloadKind>,
+) -> Result<Arc<RwLock<Routes>>> {
+    let path = match &options.routes.path {
+        Some(path) => path
+            .canonicalize()
+            .with_context(|| format!("failed to read routes file `{}`", path.display()))?,
+        None => return Ok(Default::default()),
+    };
+
+    log::info!("loading routes from `{}`", path.display());
+    let routes = Arc::new(RwLock::new(load_routes(&path)?));
+
+    if options.watch {
+        let routes = routes.clone();
+        let watched = path.parent().unwrap_or(&path).to_owned();
+        let failed = Arc::new(AtomicBool::new(false));
+        if let Err(err) = crate::notify::watch(
+            &watched,
+            notify::RecursiveMode::NonRecursive,
+            move |events| {
+                on_change(
+                    path.clone(),
+                    events,
+                    reload_tx.clone(),
+                    routes.clone(),
+                    failed.clone(),
+                )
+            },
+        ) {
+            log::error!("{:#}", err);
+        }
+    }
+
+    Ok(routes)
+}
+
+#[context("failed to load routes from `{}`", path.display())]
+fn load_routes(path: &Path) -> Result<Routes> {
+    let config: Config = serde_json::from_value(crate::value::load_file(path)?)?;
+    config
+        .routes
+        .into_iter()
+        .map(|config| Route::parse(&config))
+        .collect::<Result<_>>()
+        .map(Routes)
+}
+
+impl Route {
+    #[context("invalid route `{}`", config.path)]
+    fn parse(config: &RouteConfig) -> Result<Route> {
+        let segments: Vec<Segment> = config
+            .path
+            .strip_prefix('/')
+            .ok_or_else(|| anyhow::format_err!("path must start with `/`"))?
+            .split('/')
+            .map(|segment| {
+                match segment
+                    .strip_prefix('{')
+                    .and_then(|segment| segment.strip_suffix('}'))
+                {
+                    Some(name) => Segment::Param(name.to_owned()),
+                    None => Segment::Literal(segment.to_owned()),
+                }
+            })
+            .collect();
+
+        if let Some(data) = &config.data {
+            let mut unknown = data.clone();
+            for segment in &segments {
+                if let Segment::Param(name) = segment {
+                    unknown = unknown.replace(&format!("{{{}}}", name), "");
+                }
+            }
+            if unknown.contains('{') {
+                anyhow::bail!("data pointer `{}` uses an unknown parameter", data);
+            }
+        }
+
+        Ok(Route {
+            segments,
+            template: config.template.clone(),
+            data: config.data.clone(),
+            cache_control: config.cache_control.clone(),
+        })
+    }
+
+    /// Returns the path parameters if the URL path segments match this route.
+    fn matches(&self, segments: &[String]) -> Option<Map<String, Value>> {
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = Map::new();
+        for (segment, pattern) in segments.iter().zip(&self.segments) {
+            match pattern {
+                Segment::Literal(literal) if literal == segment => {}
+                Segment::Param(name) if !segment.is_empty() => {
+                    params.insert(name.clone(), Value::String(segment.clone()));
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+impl Routes {
+    /// Finds the route matching the URL `path`, returning it along with the JSON pointer to
+    /// render its template against and the path parameters.
+    fn resolve(
+        &self,
+        path: &str,
+        templates: &Templates,
+    ) -> Option<(&Route, String, Map<String, Value>)> {
+        let segments = path
+            .strip_prefix('/')?
+            .split('/')
+            .map(|segment| urlencoding::decode(segment).ok())
+            .collect::<Option<Vec<_>>>()?;
+
+        self.0.iter().find_map(|route| {
+            let params = route.matches(&segments)?;
+            let pointer = match &route.data {
+                Some(data) => params.iter().fold(data.clone(), |pointer, (name, value)| {
+                    let value = value.as_str().unwrap_or_default();
+                    pointer.replace(&format!("{{{}}}", name), &escape(value))
+                }),
+                None => templates
+                    .front_matter(&route.template)
+                    .data
+                    .clone()
+                    .unwrap_or_default(),
+            };
+            Some((route, pointer, params))
+        })
+    }
+
+    /// Returns the URL path, template and JSON pointer of every route without parameters, as
+    /// they would be served. Routes with parameters are skipped with a warning, since the URLs
+    /// they match can't be listed.
+    pub fn exported(&self, templates: &Templates) -> Vec<(String, String, String)> {
+        let mut exported: Vec<(String, String, String)> = Vec::new();
+        for route in &self.0 {
+            let mut path = String::new();
+            for segment in &route.segments {
+                match segment {
+                    Segment::Literal(literal) => {
+                        path.push('/');
+                        path.push_str(literal);
+                    }
+                    Segment::Param(name) => {
+                        log::warn!(
+                            "skipping route for template `{}`: parameter `{}` can't be exported",
+                            route.template,
+                            name
+                        );
+                        path.clear();
+                        break;
+                    }
+                }
+            }
+            if path.is_empty() || exported.iter().any(|(exported, ..)| *exported == path) {
+                continue;
+            }
+
+            // An earlier route with parameters may match the path first.
+            if let Some((route, pointer, _)) = self.resolve(&path, templates) {
+                exported.push((path, route.template.clone(), pointer));
+            }
+        }
+        exported
+    }
+}
+
+pub fn routes(
+    options: &render::Options,
+    routes: Arc<RwLock<Routes>>,
+    templates: Arc<RwLock<Arc<Templates>>>,
+    value_rx: Receiver<Versioned>,
+    cache: RenderCache,
+    dependencies: PageDependencies,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let options = options.clone();
+    warp::get()
+        .and(warp::path::full())
+        .and_then(move |path: warp::path::FullPath| {
+            let options = options.clone();
+            let routes = routes.clone();
+            let templates = templates.clone();
+            let value_rx = value_rx.clone();
+            let cache = cache.clone();
+            let dependencies = dependencies.clone();
+            async move {
+                // Render without holding the locks or borrowing the value, so reloads and value
+                // updates aren't blocked meanwhile.
+                let templates = templates.read().await.clone();
+                let (name, cache_control, pointer, params) = {
+                    let routes = routes.read().await;
+                    match routes.resolve(path.as_str(), &templates) {
+                        Some((route, pointer, params)) => (
+                            route.template.clone(),
+                            route.cache_control.clone(),
+                            pointer,
+                            params,
+                        ),
+                        None => return Err(warp::reject::not_found()),
+                    }
+                };
+                let name = name.as_str();
+
+                if !templates.is_page(name) {
+                    log::error!(
+                        "template `{}` for route `{}` does not exist",
+                        name,
+                        path.as_str()
+                    );
+                    return Err(warp::reject::not_found());
+                }
+
+                let params = Value::Object(params);
+                let value = value_rx.borrow().clone();
+                Ok(render_page(
+                    &options, &templates, &cache, name, &pointer, &params, &value,
+                )
+                .map(|mut response| {
+                    dependencies.record(path.as_str(), &templates, name, &pointer);
+                    if let Some(cache_control) = cache_control {
+                        response.headers_mut().insert(CACHE_CONTROL, cache_control);
+                    }
+                    response
+                })
+                .unwrap_or_else(|err| page_error(&options, &templates, path.as_str(), err)))
+            }
+        })
+}
+
+fn escape(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+async fn on_change(
+    path: PathBuf,
+    events: Vec<notify::Event>,
+    reload_tx: broadcast::Sender<ReloadKind>,
+    routes: Arc<RwLock<Routes>>,
+    failed: Arc<AtomicBool>,
+) {
+    let modified = events.iter().any(|event| {
+        !matches!(
+            event.kind,
+            notify::EventKind::Access(_) | notify::EventKind::Other
+        ) && event.paths.contains(&path)
+    });
+    if !modified {
+        return;
+    }
+
+    log::info!("reloading routes from `{}`", path.display());
+    match load_routes(&path) {
+        Ok(new_routes) => {
+            *routes.write().await = new_routes;
+            if failed.swap(false, Ordering::SeqCst) {
+                reload_tx
+                    .send(ReloadKind::Resolved(ReloadSource::Routes))
+                    .ok();
+            }
+            reload_tx.send(ReloadKind::Page).ok();
+        }
+        Err(err) => {
+            log::error!("failed reloading routes: {:#}", err);
+            failed.store(true, Ordering::SeqCst);
+            reload_tx
+                .send(ReloadKind::Error(ReloadError::new(
+                    ReloadSource::Routes,
+                    &err,
+                )))
+                .ok();
+        }
+    }
+}
@@ -1,18 +1,29 @@
 use std::convert::Infallible;
 use std::fs::File;
 use std::future::Future;
-use std::io::{BufReader, Seek, SeekFrom};
+use std::io::{self, BufReader, Seek, SeekFrom, Write};
+use std::mem;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 
 use anyhow::{format_err, Context, Result};
+use bytes::Bytes;
 use fn_error_context::context;
-use futures::{future, FutureExt, TryFutureExt};
+use futures::{future, stream, FutureExt, TryFutureExt};
+use http::header::{
+    HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    LAST_MODIFIED, VARY,
+};
+use http::StatusCode;
+use hyper::body::HttpBody as _;
 use hyper::server::{conn::AddrIncoming, conn::AddrStream, Server};
 use hyper::service::{make_service_fn, Service};
 use hyper::Body;
+use mime_guess::mime::Mime;
 use rustls::internal::pemfile;
 use structopt::StructOpt;
+use warp::reply::Response;
+use warp::{Filter as _, Reply};
 
 use crate::tls::{TlsAcceptor, TlsStream};
 
@@ -51,6 +62,45 @@ pub struct Options {
         parse(from_os_str)
     )]
     tls_key: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Disable compressing responses with brotli, zstd or gzip, and serving precompressed `.br` and `.gz` static files"
+    )]
+    no_compression: bool,
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        default_value = "1024",
+        help = "Minimum size of responses to compress"
+    )]
+    compression_min_size: usize,
+    /// Set while watching for changes, when precompressed files are likely outdated and the live
+    /// reload script can't be inserted into them.
+    #[structopt(skip)]
+    no_precompressed: bool,
+}
+
+impl Options {
+    /// Turns off serving precompressed static files while watching for changes, so edited files
+    /// are served and HTML pages get the live reload script.
+    pub fn disable_precompressed_when_watching(&mut self, watch: bool) {
+        if watch && !self.no_compression {
+            log::info!("not serving precompressed files while watching for changes");
+            self.no_precompressed = true;
+        }
+    }
+}
+
+/// The largest response with a known size to compress as a whole. Larger responses are compressed
+/// chunk by chunk as they are sent, like responses of unknown size.
+const MAX_BUFFERED_SIZE: u64 = 1024 * 1024;
+
+/// A content coding supported for compressing responses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
 }
 
 pub async fn run<S>(options: &Options, service: S) -> Result<()>
@@ -91,13 +141,11 @@ where
 impl Options {
     async fn resolve_addr(&self) -> Result<SocketAddr> {
         let error_message = || format!("failed to resolve host `{}`", self.host);
-        Ok(
-            tokio::net::lookup_host((self.host.as_ref(), self.port.unwrap_or(0)))
-                .await
-                .with_context(error_message)?
-                .next()
-                .with_context(error_message)?,
-        )
+        tokio::net::lookup_host((self.host.as_ref(), self.port.unwrap_or(0)))
+            .await
+            .with_context(error_message)?
+            .next()
+            .with_context(error_message)
     }
 
     fn tls_config(&self) -> Result<Option<rustls::ServerConfig>> {
@@ -143,6 +191,354 @@ impl Options {
     }
 }
 
+/// Compresses responses of `filter` with the encoding preferred by the client's `Accept-Encoding`
+/// header, if they are textual and at least the minimum size. Compressing a whole response is
+/// done on a blocking thread.
+pub fn compress<F, R>(
+    options: &Options,
+    filter: F,
+) -> impl warp::Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+where
+    F: warp::Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let enabled = !options.no_compression;
+    let min_size = options.compression_min_size;
+    warp::header::optional::<String>("accept-encoding")
+        .and(filter)
+        .and_then(
+            move |accept_encoding: Option<String>, reply: R| async move {
+                let response = reply.into_response();
+                if enabled {
+                    Ok::<_, Infallible>(
+                        compress_response(response, accept_encoding, min_size).await,
+                    )
+                } else {
+                    Ok(response)
+                }
+            },
+        )
+}
+
+async fn compress_response(
+    response: Response,
+    accept_encoding: Option<String>,
+    min_size: usize,
+) -> Response {
+    let headers = response.headers();
+    let is_compressible = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.parse::<Mime>().ok())
+        .is_some_and(|mime| crate::render::is_text(&mime) || mime.subtype() == "wasm");
+    let size = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    let is_small = size.is_some_and(|size| size < min_size as u64);
+    let status = response.status();
+    if !is_compressible
+        || is_small
+        || headers.contains_key(CONTENT_ENCODING)
+        || headers.contains_key(CONTENT_RANGE)
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let encoding = match accept_encoding
+        .and_then(|accept_encoding| Encoding::negotiate(&accept_encoding, &Encoding::ALL))
+    {
+        Some(encoding) => encoding,
+        None => return Response::from_parts(parts, body),
+    };
+
+    let body = if size.is_none_or(|size| size > MAX_BUFFERED_SIZE) {
+        // The body may be streamed as it is produced or be large, so compress it chunk by chunk
+        // instead of waiting for all of it.
+        match Encoder::new(encoding) {
+            Ok(encoder) => encoder.encode_stream(body),
+            Err(err) => {
+                log::error!(
+                    "failed to compress response with {}: {}",
+                    encoding.name(),
+                    err
+                );
+                return Response::from_parts(parts, body);
+            }
+        }
+    } else {
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("failed to read response body: {}", err);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        if body.len() < min_size {
+            return Response::from_parts(parts, body.into());
+        }
+
+        let compressed = {
+            let body = body.clone();
+            tokio::task::spawn_blocking(move || encoding.encode(&body))
+                .await
+                .map_err(io::Error::other)
+                .and_then(|result| result)
+        };
+        match compressed {
+            Ok(compressed) if compressed.len() < body.len() => compressed.into(),
+            Ok(_) => return Response::from_parts(parts, body.into()),
+            Err(err) => {
+                log::error!(
+                    "failed to compress response with {}: {}",
+                    encoding.name(),
+                    err
+                );
+                return Response::from_parts(parts, body.into());
+            }
+        }
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    // The compressed body is not byte-for-byte identical to the original, so its ETag is weak.
+    if let Some(etag) = parts.headers.get(ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            parts
+                .headers
+                .insert(ETAG, HeaderValue::from_bytes(&weak).unwrap());
+        }
+    }
+    Response::from_parts(parts, body)
+}
+
+/// Serves the `.br` or `.gz` sibling of a file in the `base` directory, if the client accepts it.
+pub fn precompressed(
+    options: &Options,
+    base: PathBuf,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let enabled = !options.no_compression && !options.no_precompressed;
+    warp::get()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then(
+            move |path: warp::path::FullPath, accept_encoding: Option<String>| {
+                let base = base.clone();
+                async move {
+                    let accept_encoding = match accept_encoding {
+                        Some(accept_encoding) if enabled => accept_encoding,
+                        _ => return Err(warp::reject::not_found()),
+                    };
+                    let file =
+                        static_file(&base, path.as_str()).ok_or_else(warp::reject::not_found)?;
+                    let metadata = match tokio::fs::metadata(&file).await {
+                        Ok(metadata) if metadata.is_file() => metadata,
+                        _ => return Err(warp::reject::not_found()),
+                    };
+
+                    let mut available = Vec::new();
+                    for &encoding in &Encoding::ALL {
+                        if let Some(sibling) = encoding.sibling(&file) {
+                            if tokio::fs::metadata(&sibling)
+                                .await
+                                .is_ok_and(|metadata| metadata.is_file())
+                            {
+                                available.push(encoding);
+                            }
+                        }
+                    }
+                    let encoding = Encoding::negotiate(&accept_encoding, &available)
+                        .ok_or_else(warp::reject::not_found)?;
+                    let body = match tokio::fs::read(encoding.sibling(&file).unwrap()).await {
+                        Ok(body) => body,
+                        Err(err) => {
+                            log::error!("failed to read precompressed file: {}", err);
+                            return Err(warp::reject::not_found());
+                        }
+                    };
+
+                    let mime = mime_guess::from_path(&file).first_or_octet_stream();
+                    let mut response = Response::new(body.into());
+                    let headers = response.headers_mut();
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_str(mime.as_ref()).unwrap());
+                    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+                    headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+                    if let Ok(modified) = metadata.modified() {
+                        headers.insert(LAST_MODIFIED, crate::render::http_date(modified.into()));
+                    }
+                    Ok(response)
+                }
+            },
+        )
+}
+
+/// Resolves the URL `path` of a static file within `base`, rejecting paths outside it.
+fn static_file(base: &Path, path: &str) -> Option<PathBuf> {
+    let path = urlencoding::decode(path).ok()?;
+    let mut file = base.to_owned();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains('\\') => return None,
+            segment => file.push(segment),
+        }
+    }
+    if path.ends_with('/') {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+impl Encoding {
+    /// All supported encodings, in order of preference when the client accepts several equally.
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Returns the path of the precompressed version of `file`, if supported for this encoding.
+    fn sibling(self, file: &Path) -> Option<PathBuf> {
+        let extension = match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+            Encoding::Zstd => return None,
+        };
+        let mut sibling = file.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+        Some(sibling.into())
+    }
+
+    fn encode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(self)?;
+        encoder.writer().write_all(body)?;
+        encoder.finish()
+    }
+
+    /// Picks the encoding from `candidates` with the highest quality in an `Accept-Encoding`
+    /// header, or `None` if the client accepts none of them.
+    fn negotiate(accept_encoding: &str, candidates: &[Encoding]) -> Option<Encoding> {
+        let mut qualities = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let coding = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.parse::<f32>().ok());
+            if let Some(quality) = quality {
+                qualities.push((coding, quality));
+            }
+        }
+
+        let quality_of = |encoding: Encoding| {
+            let find = |name: &str| {
+                qualities
+                    .iter()
+                    .find(|(coding, _)| coding == name)
+                    .map(|&(_, quality)| quality)
+            };
+            find(encoding.name()).or_else(|| find("*")).unwrap_or(0.0)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in candidates {
+            let quality = quality_of(encoding);
+            if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+/// Compresses data with an `Encoding`, writing the output to a buffer.
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                5,
+                22,
+            ))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?),
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Brotli(writer) => writer,
+            Encoder::Zstd(writer) => writer,
+            Encoder::Gzip(writer) => writer,
+        }
+    }
+
+    /// Compresses `chunk`, returning all output so far so it can be sent before the next chunk.
+    fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let writer = self.writer();
+        writer.write_all(chunk)?;
+        writer.flush()?;
+        let buffer = match self {
+            Encoder::Brotli(writer) => writer.get_mut(),
+            Encoder::Zstd(writer) => writer.get_mut(),
+            Encoder::Gzip(writer) => writer.get_mut(),
+        };
+        Ok(mem::take(buffer))
+    }
+
+    /// Returns the remaining output, after any returned by `write_chunk`.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(writer) => Ok(writer.into_inner()),
+            Encoder::Zstd(writer) => writer.finish(),
+            Encoder::Gzip(writer) => writer.finish(),
+        }
+    }
+
+    fn encode_stream(self, body: Body) -> Body {
+        let chunks = stream::unfold(Some((body, self)), |state| async move {
+            let (mut body, mut encoder) = state?;
+            match body.data().await {
+                Some(Ok(chunk)) => {
+                    let output = encoder.write_chunk(&chunk).map(Bytes::from);
+                    Some((output, Some((body, encoder))))
+                }
+                Some(Err(err)) => Some((Err(io::Error::other(err)), None)),
+                None => Some((encoder.finish().map(Bytes::from), None)),
+            }
+        });
+        Body::wrap_stream(chunks)
+    }
+}
+
 fn ctrl_c() -> impl Future<Output = ()> {
     tokio::signal::ctrl_c()
         .or_else(|err| {
@@ -153,3 +549,37 @@ fn ctrl_c() -> impl Future<Output = ()> {
             log::info!("Received SIGINT, shutting down server");
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_quality() {
+        let negotiate = |accept_encoding| Encoding::negotiate(accept_encoding, &Encoding::ALL);
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate("br;q=0.5, zstd;q=0.8, gzip"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("GZIP ; q=0.9"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn negotiate_wildcard_and_rejections() {
+        let negotiate = |accept_encoding| Encoding::negotiate(accept_encoding, &Encoding::ALL);
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip;q=0, *;q=0.1"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0, zstd;q=0, gzip;q=0, *"), None);
+        assert_eq!(negotiate("br;q=invalid, gzip;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("br, zstd", &[Encoding::Gzip]), None);
+        assert_eq!(
+            Encoding::negotiate("br;q=0.2, gzip;q=0.3", &[Encoding::Brotli, Encoding::Gzip]),
+            Some(Encoding::Gzip)
+        );
+    }
+}
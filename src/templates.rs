@@ -1,96 +1,634 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
 use fn_error_context::context;
-use handlebars::{Handlebars, TemplateFileError};
+use handlebars::template::{Template, TemplateElement};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, Output, RenderContext, RenderError, Renderable,
+    ScopedJson, StringOutput,
+};
+use http::header::HeaderValue;
+use http::StatusCode;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
 use tokio::sync::{broadcast, RwLock};
+use walkdir::WalkDir;
 
-use crate::reload::ReloadKind;
+use crate::reload::{ReloadError, ReloadKind, ReloadSource};
+
+/// Directories containing templates which are only used as partials, not rendered as pages.
+const PARTIAL_DIRS: &[&str] = &["partials/", "layouts/"];
+
+/// Templates rendered for failed requests, which are not served as pages themselves.
+const ERROR_PAGES: &[&str] = &["404", "500"];
+
+pub struct Templates {
+    /// Registry escaping for HTML, also used for other markup formats like XML.
+    handlebars: Handlebars<'static>,
+    /// Copy of `handlebars` escaping for use inside JSON strings.
+    json_handlebars: Handlebars<'static>,
+    /// Copy of `handlebars` without escaping, for formats like plain text or CSS.
+    plain_handlebars: Handlebars<'static>,
+    partials: HashSet<String>,
+    front_matter: HashMap<String, FrontMatter>,
+    generation: u64,
+    modified: DateTime<Utc>,
+}
+
+/// Counts how many times templates have been loaded, to identify output of outdated templates.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Metadata from the YAML block at the start of a template, delimited by `---` lines.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+    #[serde(deserialize_with = "deserialize_header_value")]
+    pub content_type: Option<HeaderValue>,
+    #[serde(deserialize_with = "deserialize_status")]
+    pub status: Option<StatusCode>,
+    /// JSON pointer to the subtree of the value the template renders. The pointer from the URL
+    /// path is resolved relative to it.
+    pub data: Option<String>,
+    pub layout: Option<String>,
+    /// The `Cache-Control` header of the page, which is `no-cache` if not set.
+    #[serde(deserialize_with = "deserialize_header_value")]
+    pub cache_control: Option<HeaderValue>,
+    /// Send the page as it is rendered instead of once it is complete, for large pages. Streamed
+    /// pages are not cached or minified, have no `ETag`, and are cut off if rendering fails.
+    pub stream: bool,
+    /// All fields as written, available to the template through the `front_matter` helper.
+    #[serde(skip)]
+    pub value: Value,
+}
 
 #[context("failed to load templates from directory: `{}`", options.base.display())]
 pub fn load(
     options: &crate::Options,
     reload_tx: broadcast::Sender<ReloadKind>,
-) -> Result<Arc<RwLock<Handlebars<'static>>>> {
+) -> Result<Arc<RwLock<Arc<Templates>>>> {
     log::info!(
         "loading templates from directory `{}`",
         options.base.display()
     );
-    let handlebars = load_templates(&options.base)?;
+    let templates = load_templates(&options.base)?;
 
-    let handlebars = Arc::new(RwLock::new(handlebars));
+    // Requests hold their own reference to the templates, so pages streamed while templates are
+    // reloaded are finished with the templates they started with.
+    let templates = Arc::new(RwLock::new(Arc::new(templates)));
 
     if options.watch {
-        let handlebars_clone = handlebars.clone();
+        let templates_clone = templates.clone();
         let base = options.base.clone();
-        if let Err(err) = crate::notify::watch(&options.base, move |events| {
-            on_change(
-                base.clone(),
-                events,
-                reload_tx.clone(),
-                handlebars_clone.clone(),
-            )
-        }) {
+        let data_paths = options.value.data_paths();
+        let failed = Arc::new(AtomicBool::new(false));
+        if let Err(err) = crate::notify::watch(
+            &options.base,
+            notify::RecursiveMode::Recursive,
+            move |events| {
+                on_change(
+                    base.clone(),
+                    data_paths.clone(),
+                    events,
+                    reload_tx.clone(),
+                    templates_clone.clone(),
+                    failed.clone(),
+                )
+            },
+        ) {
             log::error!("{:#}", err);
         }
     }
 
-    Ok(handlebars)
+    Ok(templates)
 }
 
-fn load_templates(path: &Path) -> Result<Handlebars<'static>> {
+pub fn load_templates(path: &Path) -> Result<Templates> {
     let mut handlebars = Handlebars::new();
-    handlebars
-        .register_templates_directory(".hbs", &path)
-        .map_err(convert_template_file_error)?;
+    let mut front_matter = register_templates(&mut handlebars, path)?;
+    let partials = register_partials(&mut handlebars);
+    for name in &partials {
+        front_matter.remove(name);
+    }
+    crate::helpers::register(&mut handlebars);
+    register_script_helpers(&mut handlebars, &path.join("helpers"))?;
     handlebars.set_strict_mode(true);
-    Ok(handlebars)
+
+    let mut json_handlebars = handlebars.clone();
+    json_handlebars.register_escape_fn(json_escape);
+    let mut plain_handlebars = handlebars.clone();
+    plain_handlebars.register_escape_fn(handlebars::no_escape);
+
+    Ok(Templates {
+        handlebars,
+        json_handlebars,
+        plain_handlebars,
+        partials,
+        front_matter,
+        generation: GENERATION.fetch_add(1, Ordering::Relaxed),
+        modified: Utc::now(),
+    })
 }
 
-async fn on_change(
-    path: PathBuf,
-    events: Vec<notify::Event>,
-    reload_tx: broadcast::Sender<ReloadKind>,
-    handlebars: Arc<RwLock<Handlebars<'static>>>,
-) {
-    let mut any_modified = false;
-    let mut templates_modified = false;
+impl Templates {
+    /// Returns whether `name` is a template that can be rendered as a page.
+    pub fn is_page(&self, name: &str) -> bool {
+        self.handlebars.has_template(name)
+            && !self.partials.contains(name)
+            && !ERROR_PAGES.contains(&name)
+    }
+
+    /// Returns a number which differs for each time templates are loaded.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns when the templates were loaded.
+    pub fn modified(&self) -> DateTime<Utc> {
+        self.modified
+    }
+
+    pub fn has_template(&self, name: &str) -> bool {
+        self.handlebars.has_template(name)
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = &str> {
+        self.handlebars
+            .get_templates()
+            .keys()
+            .map(String::as_str)
+            .filter(move |name| !self.partials.contains(*name) && !ERROR_PAGES.contains(name))
+    }
+
+    /// Returns the page served at the file name `file` of a URL path. Templates without an
+    /// extension in their name, like `index.hbs`, are served as `.html` files, and others like
+    /// `feed.xml.hbs` at their name.
+    pub fn page_for_file<'a>(&self, file: &'a str) -> Option<&'a str> {
+        match file.rsplit_once('.') {
+            Some(_) if self.is_page(file) => Some(file),
+            Some((name, "html")) if !name.contains('.') && self.is_page(name) => Some(name),
+            _ => None,
+        }
+    }
 
-    for event in events {
-        match event.kind {
-            notify::EventKind::Access(_) | notify::EventKind::Other => continue,
-            _ => any_modified = true,
+    /// Returns the file name the page `name` is served at, the inverse of `page_for_file`.
+    pub fn file_name(&self, name: &str) -> String {
+        if name.contains('.') {
+            name.to_owned()
+        } else {
+            format!("{}.html", name)
         }
+    }
+
+    /// Returns the names of the templates rendering the page `name` may use, or `None` if it
+    /// uses partials whose names are only known at render time.
+    pub fn dependencies(&self, name: &str) -> Option<HashSet<String>> {
+        let mut names: HashSet<String> = ERROR_PAGES.iter().map(|&name| name.to_owned()).collect();
+        names.insert(name.to_owned());
+
+        let mut stack = vec![name.to_owned()];
+        while let Some(name) = stack.pop() {
+            let template = match self.handlebars.get_template(&name) {
+                Some(template) => template,
+                None => continue,
+            };
+            let mut partials = Vec::new();
+            if !collect_partials(template, &mut partials) {
+                return None;
+            }
+            for partial in partials {
+                if names.insert(partial.clone()) {
+                    stack.push(partial);
+                }
+            }
+        }
+        Some(names)
+    }
+
+    /// Returns the front matter of the page `name`, which is empty if the template has none.
+    pub fn front_matter(&self, name: &str) -> &FrontMatter {
+        static EMPTY: FrontMatter = FrontMatter {
+            title: None,
+            content_type: None,
+            status: None,
+            data: None,
+            layout: None,
+            cache_control: None,
+            stream: false,
+            value: Value::Null,
+        };
+        self.front_matter.get(name).unwrap_or(&EMPTY)
+    }
+
+    /// Renders the template `name`, with `params` available through the `params` helper.
+    pub fn render(&self, name: &str, value: &Value, params: &Value) -> Result<String, RenderError> {
+        let mut output = StringOutput::new();
+        self.render_to(name, value, params, &mut output)?;
+        output.into_string().map_err(RenderError::from)
+    }
+
+    /// Renders the template `name` to `output`, as it is written.
+    pub fn render_to(
+        &self,
+        name: &str,
+        value: &Value,
+        params: &Value,
+        output: &mut impl Output,
+    ) -> Result<(), RenderError> {
+        let handlebars = self.registry(name);
+        let template = handlebars
+            .get_template(name)
+            .ok_or_else(|| RenderError::new(format!("Template not found: {}", name)))?;
+
+        let ctx = Context::wraps(value)?;
+        let mut rc = RenderContext::new(template.name.as_ref());
+        rc.register_local_helper(
+            "front_matter",
+            Box::new(FieldHelper("front_matter", &self.front_matter(name).value)),
+        );
+        rc.register_local_helper("params", Box::new(FieldHelper("params", params)));
+
+        template.render(handlebars, &ctx, &mut rc, output)
+    }
 
-        if event
-            .paths
+    /// Returns the registry with the escaping for the format given by the extension in `name`.
+    fn registry(&self, name: &str) -> &Handlebars<'static> {
+        let file = name.rsplit('/').next().unwrap_or(name);
+        match file.rsplit_once('.').map(|(_, extension)| extension) {
+            None | Some("html") | Some("htm") | Some("xml") | Some("svg") | Some("rss")
+            | Some("atom") => &self.handlebars,
+            Some("json") => &self.json_handlebars,
+            Some(_) => &self.plain_handlebars,
+        }
+    }
+}
+
+/// Adds the names of the partials used by `template` to `partials`. Returns `false` if a partial
+/// name is computed at render time.
+fn collect_partials(template: &Template, partials: &mut Vec<String>) -> bool {
+    template.elements.iter().all(|element| match element {
+        TemplateElement::PartialExpression(partial) | TemplateElement::PartialBlock(partial) => {
+            match partial.name.as_name() {
+                Some(name) if name.starts_with('@') => {}
+                Some(name) => partials.push(name.to_owned()),
+                None => return false,
+            }
+            partial
+                .template
+                .iter()
+                .all(|template| collect_partials(template, partials))
+        }
+        TemplateElement::DecoratorBlock(decorator) => decorator
+            .template
+            .iter()
+            .all(|template| collect_partials(template, partials)),
+        TemplateElement::HelperBlock(helper)
+        | TemplateElement::HtmlExpression(helper)
+        | TemplateElement::Expression(helper) => helper
+            .template
             .iter()
-            .any(|event_path| event_path.extension() == Some("hbs".as_ref()))
+            .chain(&helper.inverse)
+            .all(|template| collect_partials(template, partials)),
+        _ => true,
+    })
+}
+
+/// Escapes `data` for use inside a JSON string literal.
+fn json_escape(data: &str) -> String {
+    let quoted = Value::from(data).to_string();
+    quoted[1..quoted.len() - 1].to_owned()
+}
+
+/// Looks up a field in an object specific to the page being rendered, like its front matter in
+/// `{{front_matter "title"}}`. Without a parameter it returns the whole object.
+struct FieldHelper<'a>(&'static str, &'a Value);
+
+impl HelperDef for FieldHelper<'_> {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let value = match h.param(0).map(|param| param.value()) {
+            None => self.1.clone(),
+            Some(Value::String(key)) => self.1.get(key).cloned().unwrap_or(Value::Null),
+            Some(_) => {
+                return Err(RenderError::new(format!(
+                    "Param of helper \"{}\" must be a string",
+                    self.0
+                )))
+            }
+        };
+        Ok(ScopedJson::Derived(value))
+    }
+}
+
+/// Registers every `.hbs` file under `path` by its relative path without the extension,
+/// stripping and parsing front matter. Returns the front matter of each template that has it.
+fn register_templates(
+    handlebars: &mut Handlebars<'static>,
+    path: &Path,
+) -> Result<HashMap<String, FrontMatter>> {
+    let mut front_matters = HashMap::new();
+    for entry in WalkDir::new(path).min_depth(1) {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy();
+        if !entry.file_type().is_file()
+            || !file_name.ends_with(".hbs")
+            || file_name.starts_with('.')
+            || file_name.starts_with('#')
         {
-            templates_modified = true;
-            break;
+            continue;
+        }
+
+        let name = template_name(entry.path().strip_prefix(path)?);
+
+        let source = fs::read_to_string(entry.path())
+            .with_context(|| format!("failed to read template `{}`", entry.path().display()))?;
+        let (front_matter, source) = parse_front_matter(&source)
+            .with_context(|| format!("invalid front matter in `{}`", entry.path().display()))?;
+        let source = match &front_matter {
+            Some(FrontMatter {
+                layout: Some(layout),
+                ..
+            }) => format!("{{{{#> {0}}}}}{1}{{{{/{0}}}}}", layout, source),
+            _ => source,
+        };
+
+        handlebars.register_template_string(&name, source)?;
+        if let Some(front_matter) = front_matter {
+            front_matters.insert(name, front_matter);
+        }
+    }
+    Ok(front_matters)
+}
+
+/// Returns the name of the template at `relative` to the base directory, its path without the
+/// `.hbs` extension.
+fn template_name(relative: &Path) -> String {
+    relative
+        .with_extension("")
+        .iter()
+        .map(|segment| segment.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Splits the front matter off the start of `source`. It is replaced by a comment spanning the
+/// same number of lines, so line numbers in template errors still match the file.
+fn parse_front_matter(source: &str) -> Result<(Option<FrontMatter>, String)> {
+    let rest = match source
+        .strip_prefix("---\n")
+        .or_else(|| source.strip_prefix("---\r\n"))
+    {
+        Some(rest) => rest,
+        None => return Ok((None, source.to_owned())),
+    };
+
+    let mut offset = 0;
+    let mut lines = rest.split_inclusive('\n');
+    let (yaml, body) = loop {
+        let line = lines
+            .next()
+            .context("missing closing `---` line after front matter")?;
+        if line.trim_end() == "---" {
+            break (&rest[..offset], &rest[offset + line.len()..]);
+        }
+        offset += line.len();
+    };
+
+    let value: Value = if yaml.trim().is_empty() {
+        Value::Object(Map::new())
+    } else {
+        serde_yaml::from_str(yaml)?
+    };
+    let mut front_matter = FrontMatter::deserialize(&value)?;
+    front_matter.value = value;
+
+    let newlines = source.len() - body.len();
+    let newlines = source[..newlines].matches('\n').count();
+    let source = format!("{{{{!--{}--}}}}{}", "\n".repeat(newlines), body);
+    Ok((Some(front_matter), source))
+}
+
+pub fn deserialize_header_value<'de, D>(deserializer: D) -> Result<Option<HeaderValue>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| HeaderValue::from_str(&value))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_status<'de, D>(deserializer: D) -> Result<Option<StatusCode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u16>::deserialize(deserializer)?
+        .map(StatusCode::from_u16)
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Registers templates in the `partials` and `layouts` directories under their path within that
+/// directory, so `partials/header.hbs` can be used as `{{> header}}`. Returns the names of all
+/// such templates.
+fn register_partials(handlebars: &mut Handlebars<'static>) -> HashSet<String> {
+    let names: Vec<(String, String)> = handlebars
+        .get_templates()
+        .keys()
+        .filter_map(|name| {
+            PARTIAL_DIRS
+                .iter()
+                .find_map(|dir| name.strip_prefix(dir))
+                .map(|alias| (name.clone(), alias.to_owned()))
+        })
+        .collect();
+
+    let mut partials = HashSet::new();
+    for (name, alias) in names {
+        if handlebars.has_template(&alias) {
+            log::warn!(
+                "not registering partial `{}` as `{}`: a template with that name already exists",
+                name,
+                alias
+            );
+        } else {
+            let template = handlebars.get_template(&name).unwrap().clone();
+            handlebars.register_template(&alias, template);
+            partials.insert(alias);
+        }
+        partials.insert(name);
+    }
+    partials
+}
+
+/// Registers each `<name>.rhai` file in `path` as a helper called `<name>`.
+fn register_script_helpers(handlebars: &mut Handlebars<'static>, path: &Path) -> Result<()> {
+    if !path.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.extension() != Some("rhai".as_ref()) {
+            continue;
+        }
+
+        if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+            log::debug!("registering helper `{}` from `{}`", name, path.display());
+            handlebars
+                .register_script_helper_file(name, &path)
+                .with_context(|| format!("failed to load helper `{}`", path.display()))?;
         }
     }
+    Ok(())
+}
+
+async fn on_change(
+    path: PathBuf,
+    data_paths: Vec<PathBuf>,
+    events: Vec<notify::Event>,
+    reload_tx: broadcast::Sender<ReloadKind>,
+    templates: Arc<RwLock<Arc<Templates>>>,
+    failed: Arc<AtomicBool>,
+) {
+    let modified: Vec<PathBuf> = events
+        .into_iter()
+        .filter(|event| {
+            !matches!(
+                event.kind,
+                notify::EventKind::Access(_) | notify::EventKind::Other
+            )
+        })
+        .flat_map(|event| event.paths)
+        .filter(|path| {
+            !data_paths
+                .iter()
+                .any(|data_path| path.starts_with(data_path))
+        })
+        .collect();
+    if modified.is_empty() {
+        return;
+    }
+
+    let templates_modified = modified.iter().any(|event_path| {
+        event_path.extension() == Some("hbs".as_ref())
+            || event_path.extension() == Some("rhai".as_ref())
+    });
+    let template_names = template_names(&path, &modified);
 
     if templates_modified {
         log::info!("reloading templates from directory `{}`", path.display());
         match load_templates(&path) {
-            Ok(new_handlebars) => *handlebars.write().await = new_handlebars,
-            Err(err) => log::error!("failed reloading files: {:#}", err),
+            Ok(new_templates) => *templates.write().await = Arc::new(new_templates),
+            Err(err) => {
+                log::error!("failed reloading files: {:#}", err);
+                failed.store(true, Ordering::SeqCst);
+                reload_tx
+                    .send(ReloadKind::Error(ReloadError::new(
+                        ReloadSource::Templates,
+                        &err,
+                    )))
+                    .ok();
+                return;
+            }
+        }
+        // Templates modified while loading failed weren't announced, so reload every page.
+        if failed.swap(false, Ordering::SeqCst) {
+            reload_tx
+                .send(ReloadKind::Resolved(ReloadSource::Templates))
+                .ok();
+        } else if let Some(names) = template_names {
+            reload_tx.send(ReloadKind::Templates(names)).ok();
+            return;
         }
+    } else if let Some(stylesheets) = stylesheet_paths(&path, &modified) {
+        reload_tx.send(ReloadKind::Stylesheets(stylesheets)).ok();
+        return;
     }
 
-    if any_modified {
-        reload_tx.send(ReloadKind::Page).ok();
-    }
+    reload_tx.send(ReloadKind::Page).ok();
 }
 
-fn convert_template_file_error(err: TemplateFileError) -> anyhow::Error {
-    match err {
-        TemplateFileError::TemplateError(err) => err.into(),
-        TemplateFileError::IOError(err, path) => {
-            anyhow::Error::from(err).context(format!("failed to read file `{}`", path))
+/// Returns the names the modified files are registered as if they are all templates, including
+/// the aliases of partials.
+fn template_names(base: &Path, modified: &[PathBuf]) -> Option<Vec<String>> {
+    let base = base.canonicalize().ok()?;
+    let mut names = Vec::new();
+    for path in modified {
+        if path.extension() != Some("hbs".as_ref()) {
+            return None;
+        }
+        let name = template_name(path.strip_prefix(&base).ok()?);
+        if let Some(alias) = PARTIAL_DIRS.iter().find_map(|dir| name.strip_prefix(dir)) {
+            names.push(alias.to_owned());
         }
+        names.push(name);
+    }
+    Some(names)
+}
+
+/// Returns the URL paths of the modified files if they are all stylesheets, which can be
+/// reloaded without reloading the page.
+fn stylesheet_paths(base: &Path, modified: &[PathBuf]) -> Option<Vec<String>> {
+    let base = base.canonicalize().ok()?;
+    modified
+        .iter()
+        .map(|path| {
+            if path.extension() != Some("css".as_ref()) {
+                return None;
+            }
+            let relative = path.strip_prefix(&base).ok()?;
+            let segments: Vec<_> = relative
+                .iter()
+                .map(|segment| urlencoding::encode(&segment.to_string_lossy()))
+                .collect();
+            Some(format!("/{}", segments.join("/")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn front_matter_preserves_lines() {
+        let source = "---\ntitle: Home\nstatus: 404\n---\n<h1>{{title}}</h1>\n{{#if}}\n";
+        let (front_matter, body) = parse_front_matter(source).unwrap();
+        let front_matter = front_matter.unwrap();
+        assert_eq!(front_matter.title.as_deref(), Some("Home"));
+        assert_eq!(front_matter.status, Some(StatusCode::NOT_FOUND));
+        assert_eq!(front_matter.value["title"], "Home");
+        assert_eq!(body.lines().count(), source.lines().count());
+        assert_eq!(body.lines().nth(4), Some("--}}<h1>{{title}}</h1>"));
+
+        let err = handlebars::Template::compile(&body).unwrap_err();
+        let expected = handlebars::Template::compile("\n\n\n\n<h1>{{title}}</h1>\n{{#if}}\n");
+        assert_eq!(err.line_no, expected.unwrap_err().line_no);
+    }
+
+    #[test]
+    fn front_matter_crlf_and_empty() {
+        let (front_matter, body) = parse_front_matter("---\r\n---\r\nbody").unwrap();
+        assert_eq!(front_matter.unwrap().value, Value::Object(Map::new()));
+        assert_eq!(body, "{{!--\n\n--}}body");
+    }
+
+    #[test]
+    fn front_matter_missing() {
+        let (front_matter, body) = parse_front_matter("<p>---</p>\n").unwrap();
+        assert!(front_matter.is_none());
+        assert_eq!(body, "<p>---</p>\n");
+        assert!(parse_front_matter("---\ntitle: Home\n<p></p>\n").is_err());
     }
 }
@@ -1,39 +1,706 @@
-use std::io::stdin;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io::{self, stdin, BufRead, BufReader, Read};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use anyhow::{Error, Result};
-use serde_json::{Deserializer, Value};
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use serde_json::{Deserializer, Map, Value};
+use structopt::StructOpt;
 use tokio::sync::broadcast;
-use tokio::sync::watch::{channel, Receiver};
+use tokio::sync::watch::{self, Receiver};
+use walkdir::WalkDir;
 
-use crate::reload::ReloadKind;
+use crate::reload::{ReloadError, ReloadKind, ReloadSource};
 
-pub fn receiver(reload_tx: broadcast::Sender<ReloadKind>) -> Result<Receiver<Value>> {
-    let mut stream = Deserializer::from_reader(stdin()).into_iter();
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(
+        long = "data",
+        short = "d",
+        value_name = "FILE",
+        number_of_values = 1,
+        help = "Read the value from a JSON, YAML or TOML file instead of stdin. If given multiple times, the files are merged in order",
+        parse(from_os_str)
+    )]
+    data: Vec<PathBuf>,
+    #[structopt(
+        name = "data-dir",
+        long,
+        value_name = "DIR",
+        help = "Read the value from a directory, where each JSON, YAML or TOML file becomes a key in the value",
+        parse(try_from_os_str = crate::parse_dir)
+    )]
+    data_dir: Option<PathBuf>,
+    #[structopt(
+        name = "stdin-mode",
+        long,
+        value_name = "MODE",
+        default_value = "replace",
+        possible_values = &["replace", "patch"],
+        help = "How to interpret documents read from stdin after the initial value. In `patch` mode, arrays are applied as JSON patches and other values as JSON merge patches"
+    )]
+    stdin_mode: StdinMode,
+    #[structopt(
+        name = "stdin-framing",
+        long,
+        value_name = "FRAMING",
+        default_value = "stream",
+        possible_values = &["stream", "ndjson", "length-prefixed"],
+        help = "How documents read from stdin are separated. Invalid documents are skipped in `ndjson` and `length-prefixed` modes, where each document is a single line or is preceded by its length as a 32-bit big-endian integer respectively"
+    )]
+    stdin_framing: Framing,
+    #[structopt(
+        name = "stdin-encoding",
+        long,
+        value_name = "ENCODING",
+        default_value = "json",
+        possible_values = &["json", "msgpack", "cbor"],
+        help = "The encoding of documents read from stdin. Only `json` is supported unless `--stdin-framing` is `length-prefixed`"
+    )]
+    stdin_encoding: Encoding,
+}
+
+impl Options {
+    /// Returns the canonical paths of the data files and directory, which are watched separately
+    /// from the templates.
+    pub fn data_paths(&self) -> Vec<PathBuf> {
+        self.data
+            .iter()
+            .chain(&self.data_dir)
+            .filter_map(|path| path.canonicalize().ok())
+            .collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum StdinMode {
+    Replace,
+    Patch,
+}
+
+impl FromStr for StdinMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(StdinMode::Replace),
+            "patch" => Ok(StdinMode::Patch),
+            _ => Err(format!("invalid stdin mode `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Framing {
+    Stream,
+    Ndjson,
+    LengthPrefixed,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(Framing::Stream),
+            "ndjson" => Ok(Framing::Ndjson),
+            "length-prefixed" => Ok(Framing::LengthPrefixed),
+            _ => Err(format!("invalid stdin framing `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MessagePack),
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(format!("invalid stdin encoding `{}`", s)),
+        }
+    }
+}
+
+/// The current value, with a version which is incremented whenever it changes.
+#[derive(Debug, Clone)]
+pub struct Versioned {
+    pub version: u64,
+    /// When the value last changed.
+    pub modified: DateTime<Utc>,
+    pub value: Arc<Value>,
+}
+
+/// Sends updated values to the server, notifying clients that the value has changed.
+#[derive(Clone)]
+pub struct Sender {
+    sender: Arc<Mutex<watch::Sender<Versioned>>>,
+    receiver: Receiver<Versioned>,
+    reload_tx: broadcast::Sender<ReloadKind>,
+    /// Whether the last update failed, so clients are told once the value is updated again.
+    failed: Arc<AtomicBool>,
+}
+
+impl Sender {
+    fn new(
+        initial_value: Value,
+        reload_tx: broadcast::Sender<ReloadKind>,
+    ) -> (Self, Receiver<Versioned>) {
+        let (sender, receiver) = watch::channel(Versioned {
+            version: 0,
+            modified: Utc::now(),
+            value: Arc::new(initial_value),
+        });
+        let sender = Sender {
+            sender: Arc::new(Mutex::new(sender)),
+            receiver: receiver.clone(),
+            reload_tx,
+            failed: Arc::new(AtomicBool::new(false)),
+        };
+        (sender, receiver)
+    }
+
+    pub fn set(&self, value: Value) {
+        let sender = self.sender.lock().unwrap();
+        self.broadcast(&sender, value);
+    }
+
+    /// Modifies the current value. No other updates are applied until `f` returns, and the value
+    /// is left unchanged if it returns an error.
+    pub fn update<E>(&self, f: impl FnOnce(&mut Value) -> Result<(), E>) -> Result<(), E> {
+        let sender = self.sender.lock().unwrap();
+        let mut value = Value::clone(&self.receiver.borrow().value);
+        f(&mut value)?;
+        self.broadcast(&sender, value);
+        Ok(())
+    }
+
+    /// Sends `value` if it differs from the current value, and notifies clients if the last update
+    /// failed. Must be called with the `sender` lock held, so versions are assigned in order.
+    fn broadcast(&self, sender: &watch::Sender<Versioned>, value: Value) {
+        let (version, changed) = {
+            let current = self.receiver.borrow();
+            (
+                current.version + 1,
+                changed_pointers(&current.value, &value),
+            )
+        };
+        if !changed.is_empty() {
+            sender
+                .broadcast(Versioned {
+                    version,
+                    modified: Utc::now(),
+                    value: Arc::new(value),
+                })
+                .ok();
+            self.reload_tx.send(ReloadKind::Value(changed)).ok();
+        }
+        if self.failed.swap(false, Ordering::SeqCst) {
+            self.reload_tx
+                .send(ReloadKind::Resolved(ReloadSource::Value))
+                .ok();
+        }
+    }
+
+    /// Notifies clients that the value could not be updated.
+    pub fn report_error(&self, err: &anyhow::Error) {
+        self.failed.store(true, Ordering::SeqCst);
+        self.reload_tx
+            .send(ReloadKind::Error(ReloadError::new(
+                ReloadSource::Value,
+                err,
+            )))
+            .ok();
+    }
+}
 
-    let initial_value = match stream.next() {
+/// Returns the JSON pointers of the outermost values which differ between `old` and `new`.
+fn changed_pointers(old: &Value, new: &Value) -> Vec<String> {
+    fn visit(old: &Value, new: &Value, pointer: &mut String, changed: &mut Vec<String>) {
+        let len = pointer.len();
+        match (old, new) {
+            (Value::Object(old), Value::Object(new)) => {
+                for key in old
+                    .keys()
+                    .chain(new.keys().filter(|key| !old.contains_key(*key)))
+                {
+                    pointer.push('/');
+                    pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                    match (old.get(key), new.get(key)) {
+                        (Some(old), Some(new)) => visit(old, new, pointer, changed),
+                        _ => changed.push(pointer.clone()),
+                    }
+                    pointer.truncate(len);
+                }
+            }
+            (Value::Array(old), Value::Array(new)) if old.len() == new.len() => {
+                for (idx, (old, new)) in old.iter().zip(new).enumerate() {
+                    pointer.push_str(&format!("/{}", idx));
+                    visit(old, new, pointer, changed);
+                    pointer.truncate(len);
+                }
+            }
+            (old, new) if old != new => changed.push(pointer.clone()),
+            _ => {}
+        }
+    }
+
+    let mut changed = Vec::new();
+    visit(old, new, &mut String::new(), &mut changed);
+    changed
+}
+
+pub fn channel(
+    options: &crate::Options,
+    reload_tx: broadcast::Sender<ReloadKind>,
+) -> Result<(Sender, Receiver<Versioned>)> {
+    if options.value.data.is_empty() && options.value.data_dir.is_none() {
+        log::info!("reading value from stdin");
+        read_stdin(&options.value, reload_tx)
+    } else {
+        read_files(&options.value, options.watch, reload_tx)
+    }
+}
+
+fn read_stdin(
+    options: &Options,
+    reload_tx: broadcast::Sender<ReloadKind>,
+) -> Result<(Sender, Receiver<Versioned>)> {
+    let framing = options.stdin_framing;
+    let mode = options.stdin_mode;
+    let mut documents = read_documents(framing, options.stdin_encoding)?;
+
+    let initial_value = match documents.next() {
         Some(Ok(value)) => value,
-        Some(Err(err)) => return Err(Error::new(err).context("failed to read JSON from stdin")),
+        Some(Err(err)) => return Err(err.context("failed to read value from stdin")),
         None => return Err(anyhow::format_err!("failed to read from stdin")),
     };
 
-    let (sender, receiver) = channel(initial_value);
+    let (sender, receiver) = Sender::new(initial_value, reload_tx);
 
+    let stdin_sender = sender.clone();
     tokio::task::spawn_blocking(move || {
-        for value in stream {
+        for value in documents {
             match value {
-                Ok(value) => {
-                    log::info!("got updated JSON value");
-                    sender.broadcast(value).ok();
-                    reload_tx.send(ReloadKind::Value).ok();
-                }
-                Err(err) => {
-                    log::error!("failed to read JSON from stdin: {}", err);
+                Ok(value) => match mode {
+                    StdinMode::Replace => {
+                        log::info!("got updated value");
+                        stdin_sender.set(value);
+                    }
+                    StdinMode::Patch => match apply_patch(&stdin_sender, value) {
+                        Ok(()) => log::info!("got JSON patch"),
+                        Err(err) => log::error!("failed to apply JSON patch from stdin: {}", err),
+                    },
+                },
+                // Once a JSON stream is malformed we can't find the start of the next document.
+                Err(err) if framing == Framing::Stream || err.is::<io::Error>() => {
+                    log::error!(
+                        "failed to read value from stdin, no more updates will be read: {:#}",
+                        err
+                    );
                     return;
                 }
+                Err(err) => log::error!("skipping invalid value from stdin: {:#}", err),
             }
         }
         log::info!("stdin closed");
     });
 
-    Ok(receiver)
+    Ok((sender, receiver))
+}
+
+type Documents = Box<dyn Iterator<Item = Result<Value>> + Send>;
+
+fn read_documents(framing: Framing, encoding: Encoding) -> Result<Documents> {
+    match (framing, encoding) {
+        (Framing::Stream, Encoding::Json) => Ok(Box::new(
+            Deserializer::from_reader(stdin())
+                .into_iter()
+                .map(|value| Ok(value?)),
+        )),
+        (Framing::Ndjson, Encoding::Json) => Ok(ndjson_documents(BufReader::new(stdin()))),
+        (Framing::LengthPrefixed, encoding) => {
+            let mut reader = BufReader::new(stdin());
+            Ok(Box::new(
+                iter::from_fn(move || read_frame(&mut reader).transpose())
+                    .map(move |frame| decode(&frame?, encoding)),
+            ))
+        }
+        (_, _) => Err(anyhow::format_err!(
+            "only `json` encoding is supported unless `--stdin-framing` is `length-prefixed`"
+        )),
+    }
+}
+
+/// Reads newline delimited JSON documents. Lines are read as bytes, so a line which isn't valid
+/// UTF-8 is skipped like any other invalid document instead of ending the input.
+fn ndjson_documents(mut reader: impl BufRead + Send + 'static) -> Documents {
+    Box::new(
+        iter::from_fn(move || read_line(&mut reader).transpose())
+            .filter(|line| !matches!(line, Ok(line) if line.iter().all(u8::is_ascii_whitespace)))
+            .map(|line| Ok(serde_json::from_slice(&line?)?)),
+    )
+}
+
+/// Reads a single line including its terminator, returning `None` at the end of the input.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    match reader.read_until(b'\n', &mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line)),
+    }
+}
+
+/// Reads a single length-prefixed frame, returning `None` at the end of the input.
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len) as u64;
+
+    let mut frame = Vec::new();
+    reader.take(len).read_to_end(&mut frame)?;
+    if (frame.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(frame))
+}
+
+fn decode(frame: &[u8], encoding: Encoding) -> Result<Value> {
+    match encoding {
+        Encoding::Json => Ok(serde_json::from_slice(frame)?),
+        Encoding::MessagePack => Ok(rmp_serde::from_slice(frame)?),
+        Encoding::Cbor => Ok(serde_cbor::from_slice(frame)?),
+    }
+}
+
+fn apply_patch(sender: &Sender, patch: Value) -> Result<()> {
+    if patch.is_array() {
+        let patch = json_patch::from_value(patch)?;
+        sender.update(|value| json_patch::patch(value, &patch))?;
+    } else {
+        sender.update(|value| {
+            json_patch::merge(value, &patch);
+            Result::<_, Error>::Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+fn read_files(
+    options: &Options,
+    watch: bool,
+    reload_tx: broadcast::Sender<ReloadKind>,
+) -> Result<(Sender, Receiver<Versioned>)> {
+    let data = Data::load(options)?;
+    let (sender, receiver) = Sender::new(data.value(), reload_tx);
+
+    if watch {
+        let watched = data.watched_paths();
+        let data = Arc::new(Mutex::new(data));
+
+        for (path, mode) in watched {
+            let data = data.clone();
+            let sender = sender.clone();
+            if let Err(err) = crate::notify::watch(&path, mode, move |events| {
+                on_change(events, data.clone(), sender.clone())
+            }) {
+                log::error!("{:#}", err);
+            }
+        }
+    }
+
+    Ok((sender, receiver))
+}
+
+async fn on_change(events: Vec<notify::Event>, data: Arc<Mutex<Data>>, sender: Sender) {
+    let paths: BTreeSet<PathBuf> = events
+        .into_iter()
+        .filter(|event| {
+            !matches!(
+                event.kind,
+                notify::EventKind::Access(_) | notify::EventKind::Other
+            )
+        })
+        .flat_map(|event| event.paths)
+        .collect();
+
+    let mut data = data.lock().unwrap();
+    let mut files_modified = false;
+    let mut modified = false;
+
+    for path in paths {
+        if data.files.contains(&path) {
+            files_modified = true;
+        } else if data.contains(&path) {
+            log::info!("reloading data from `{}`", path.display());
+            match data.reload_path(&path) {
+                Ok(()) => modified = true,
+                Err(err) => {
+                    log::error!("failed reloading data: {:#}", err);
+                    sender.report_error(&err);
+                }
+            }
+        }
+    }
+
+    if files_modified {
+        log::info!("reloading data files");
+        match data.reload_files() {
+            Ok(()) => modified = true,
+            Err(err) => {
+                log::error!("failed reloading data files: {:#}", err);
+                sender.report_error(&err);
+            }
+        }
+    }
+
+    if modified {
+        sender.set(data.value());
+    }
+}
+
+/// The value read from data files and directories.
+struct Data {
+    files: Vec<PathBuf>,
+    files_value: Value,
+    dir: Option<PathBuf>,
+    dir_value: Value,
+}
+
+impl Data {
+    fn load(options: &Options) -> Result<Self> {
+        let files = options
+            .data
+            .iter()
+            .map(|path| canonicalize(path))
+            .collect::<Result<Vec<_>>>()?;
+        let files_value = load_files(&files)?;
+
+        let dir = options.data_dir.as_deref().map(canonicalize).transpose()?;
+        let dir_value = match &dir {
+            Some(dir) => load_dir(dir)?,
+            None => Value::Null,
+        };
+
+        Ok(Data {
+            files,
+            files_value,
+            dir,
+            dir_value,
+        })
+    }
+
+    fn value(&self) -> Value {
+        let mut value = self.files_value.clone();
+        if self.dir.is_some() {
+            merge(&mut value, self.dir_value.clone());
+        }
+        value
+    }
+
+    fn watched_paths(&self) -> Vec<(PathBuf, notify::RecursiveMode)> {
+        let mut watched: Vec<_> = self
+            .files
+            .iter()
+            .filter_map(|path| path.parent())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|dir| (dir.to_owned(), notify::RecursiveMode::NonRecursive))
+            .collect();
+        if let Some(dir) = &self.dir {
+            watched.push((dir.clone(), notify::RecursiveMode::Recursive));
+        }
+        watched
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        match &self.dir {
+            Some(dir) => path.starts_with(dir) && path != dir,
+            None => false,
+        }
+    }
+
+    fn reload_files(&mut self) -> Result<()> {
+        self.files_value = load_files(&self.files)?;
+        Ok(())
+    }
+
+    /// Re-reads the subtree of the data directory at `path`, which may have been added, modified
+    /// or removed.
+    fn reload_path(&mut self, path: &Path) -> Result<()> {
+        let dir = self.dir.as_ref().expect("no data directory");
+        let relative = path.strip_prefix(dir)?;
+
+        if path.is_dir() {
+            let value = load_dir(path)?;
+            insert(&mut self.dir_value, &dir_segments(relative), value);
+        } else if path.is_file() {
+            if let Some(segments) = file_segments(relative) {
+                let value = load_file(path)?;
+                insert(&mut self.dir_value, &segments, value);
+            }
+        } else {
+            let segments = file_segments(relative).unwrap_or_else(|| dir_segments(relative));
+            remove(&mut self.dir_value, &segments);
+        }
+        Ok(())
+    }
+}
+
+#[context("failed to read data file `{}`", path.display())]
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    Ok(path.canonicalize()?)
+}
+
+fn load_files(paths: &[PathBuf]) -> Result<Value> {
+    let mut value = Value::Null;
+    for path in paths {
+        merge(&mut value, load_file(path)?);
+    }
+    Ok(value)
+}
+
+#[context("failed to read data directory `{}`", path.display())]
+fn load_dir(path: &Path) -> Result<Value> {
+    let mut value = Value::Object(Map::new());
+    for entry in WalkDir::new(path).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(path)?;
+        match file_segments(relative) {
+            Some(segments) => insert(&mut value, &segments, load_file(entry.path())?),
+            None => log::debug!("skipping data file `{}`", entry.path().display()),
+        }
+    }
+    Ok(value)
+}
+
+#[context("failed to read data file `{}`", path.display())]
+pub fn load_file(path: &Path) -> Result<Value> {
+    let contents = fs::read(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => Ok(serde_json::from_slice(&contents)?),
+        Some("yaml") | Some("yml") => Ok(serde_yaml::from_slice(&contents)?),
+        Some("toml") => Ok(toml::from_slice(&contents)?),
+        _ => Err(anyhow::format_err!(
+            "unknown file extension, expected one of `json`, `yaml`, `yml` or `toml`"
+        )),
+    }
+}
+
+fn is_data_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("json") | Some("yaml") | Some("yml") | Some("toml")
+    )
+}
+
+/// Gets the keys of the value for a file in the data directory, e.g. `posts/first.json` maps to
+/// `["posts", "first"]`. Returns `None` if the file should be ignored.
+fn file_segments(relative: &Path) -> Option<Vec<String>> {
+    let file_name = relative.file_name()?.to_string_lossy();
+    if file_name.starts_with('.') || !is_data_file(relative) {
+        return None;
+    }
+
+    let mut segments = dir_segments(relative.parent()?);
+    segments.push(relative.file_stem()?.to_string_lossy().into_owned());
+    Some(segments)
+}
+
+fn dir_segments(relative: &Path) -> Vec<String> {
+    relative
+        .iter()
+        .map(|segment| segment.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Sets the value at `segments`, creating any intermediate objects.
+fn insert(target: &mut Value, segments: &[String], value: Value) {
+    match segments.split_first() {
+        Some((key, rest)) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let map = target.as_object_mut().unwrap();
+            insert(map.entry(key.as_str()).or_insert(Value::Null), rest, value);
+        }
+        None => *target = value,
+    }
+}
+
+fn remove(target: &mut Value, segments: &[String]) {
+    if let (Some((key, rest)), Value::Object(map)) = (segments.split_first(), target) {
+        if rest.is_empty() {
+            map.remove(key);
+        } else if let Some(child) = map.get_mut(key) {
+            remove(child, rest);
+        }
+    }
+}
+
+/// Recursively merges objects in `source` into `target`, replacing any other values.
+fn merge(target: &mut Value, source: Value) {
+    match (target, source) {
+        (Value::Object(target), Value::Object(source)) => {
+            for (key, value) in source {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, source) => *target = source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn changed_pointers_outermost() {
+        let old = json!({"a": {"b": 1, "c": [1, 2]}, "d": [1], "e/~": 1, "f": 1});
+        let new = json!({"a": {"b": 2, "c": [1, 3]}, "d": [1, 2], "e/~": 2, "g": 1});
+        assert_eq!(
+            changed_pointers(&old, &new),
+            vec!["/a/b", "/a/c/1", "/d", "/e~1~0", "/f", "/g"]
+        );
+    }
+
+    #[test]
+    fn changed_pointers_root() {
+        assert!(changed_pointers(&json!({"a": [1]}), &json!({"a": [1]})).is_empty());
+        assert_eq!(changed_pointers(&json!({"a": 1}), &json!([1])), vec![""]);
+        assert_eq!(changed_pointers(&json!(1), &json!(2)), vec![""]);
+    }
+
+    #[test]
+    fn ndjson_skips_invalid_lines() {
+        let input = b"{\"a\":1}\n\xff\xfe\n\n{\"a\":2}".to_vec();
+        let documents = ndjson_documents(io::Cursor::new(input)).collect::<Vec<_>>();
+        assert_eq!(documents.len(), 3);
+        assert_eq!(documents[0].as_ref().unwrap(), &json!({"a": 1}));
+        assert!(!documents[1].as_ref().unwrap_err().is::<io::Error>());
+        assert_eq!(documents[2].as_ref().unwrap(), &json!({"a": 2}));
+    }
 }